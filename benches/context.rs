@@ -0,0 +1,32 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+extern crate fringe;
+
+mod harness;
+
+use fringe::OsStack;
+use fringe::context::Context;
+
+fn swap() {
+  let stack = OsStack::new(4 << 20).unwrap();
+  unsafe {
+    // This deliberately does not ignore the value, to measure the time it takes
+    // to move it between registers.
+    let loopback = Context::new(&stack, |mut caller: Context, mut arg: usize| -> (Context, usize) {
+      loop { (caller, arg) = caller.switch(arg) }
+    });
+    let mut context = Some(loopback);
+
+    harness::bench("swap", || for _ in 0..10 {
+      context = Some(context.take().unwrap().switch(0).0);
+    });
+  }
+}
+
+fn main() {
+  swap();
+}
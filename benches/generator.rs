@@ -4,18 +4,92 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-#![feature(test)]
-extern crate test;
 extern crate fringe;
 
-use fringe::{OsStack, Generator};
+mod harness;
 
-#[bench]
-fn generate(b: &mut test::Bencher) {
+use std::hint::black_box;
+use fringe::{OsStack, StackPool, Generator};
+use fringe::generator::Yielder;
+
+fn generate() {
   let stack = OsStack::new(0).unwrap();
   let mut identity = Generator::new(stack, move |yielder, mut input| {
     loop { input = yielder.suspend(input) }
   });
 
-  b.iter(|| for _ in 0..10 { test::black_box(identity.resume(test::black_box(0))); });
+  harness::bench("generate", || for _ in 0..10 { black_box(identity.resume(black_box(0i32))); });
+}
+
+fn generate_pair() {
+  let stack = OsStack::new(0).unwrap();
+  let mut identity = Generator::new(stack, move |yielder, mut input| {
+    loop { input = yielder.suspend(input) }
+  });
+
+  harness::bench("generate_pair", || for _ in 0..10 {
+    black_box(identity.resume(black_box((0usize, 0usize))));
+  });
+}
+
+fn delegate() {
+  let stack = OsStack::new(0).unwrap();
+  let mut outer = Generator::new(stack, move |yielder, input| {
+    let stack = OsStack::new(0).unwrap();
+    let mut inner = Generator::new(stack, move |yielder, mut input| {
+      loop { input = yielder.suspend(input) }
+    });
+    yielder.delegate(&mut inner, input)
+  });
+
+  harness::bench("delegate", || for _ in 0..10 { black_box(outer.resume(black_box(0i32))); });
+}
+
+fn noop(_yielder: &mut Yielder<(), ()>, _: ()) {}
+
+fn create() {
+  let mut stack = Some(OsStack::new(0).unwrap());
+
+  harness::bench("create", || {
+    let mut generator = Generator::new(stack.take().unwrap(), noop);
+    black_box(generator.resume(()));
+    stack = Some(generator.unwrap());
+  });
+}
+
+fn create_unpooled() {
+  harness::bench("create_unpooled", || {
+    let mut generator = Generator::new(OsStack::new(0).unwrap(), noop);
+    black_box(generator.resume(()));
+  });
+}
+
+fn create_pooled() {
+  let pool = StackPool::new();
+
+  harness::bench("create_pooled", || {
+    let mut generator = Generator::new(pool.get(0).unwrap(), noop);
+    black_box(generator.resume(()));
+  });
+}
+
+fn reset() {
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, noop);
+  generator.resume(());
+
+  harness::bench("reset", || {
+    generator.reset(noop);
+    black_box(generator.resume(()));
+  });
+}
+
+fn main() {
+  generate();
+  generate_pair();
+  delegate();
+  create();
+  create_unpooled();
+  create_pooled();
+  reset();
 }
@@ -0,0 +1,35 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A minimal replacement for the unstable `#[bench]` harness.
+
+use std::time::{Duration, Instant};
+
+const SAMPLES: usize = 11;
+
+/// Runs `f` repeatedly and prints the median time per iteration,
+/// in the same format as `cargo bench` on nightly.
+pub fn bench<F: FnMut()>(name: &str, mut f: F) {
+  // Find an iteration count that takes long enough to measure reliably.
+  let mut iters: u32 = 1;
+  while time(iters, &mut f) < Duration::from_millis(10) {
+    iters *= 2;
+  }
+
+  let mut samples: Vec<Duration> = (0..SAMPLES).map(|_| time(iters, &mut f) / iters).collect();
+  samples.sort();
+  let median = samples[SAMPLES / 2];
+  let spread = samples[SAMPLES - 1] - samples[0];
+  println!("test {} ... bench: {:>10} ns/iter (+/- {})",
+           name, median.as_nanos(), spread.as_nanos());
+}
+
+fn time<F: FnMut()>(iters: u32, f: &mut F) -> Duration {
+  let start = Instant::now();
+  for _ in 0..iters { f() }
+  start.elapsed()
+}
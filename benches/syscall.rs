@@ -4,32 +4,32 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-#![cfg(target_os = "linux")]
-#![feature(asm, test)]
-extern crate test;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "x86")))]
+mod harness;
 
-#[cfg(target_arch = "x86_64")]
-#[bench]
-fn syscall(b: &mut test::Bencher) {
-  b.iter(|| unsafe {
-    asm!("movq $$102, %rax\n\
-          syscall"
-         :
-         :
-         : "rax", "rcx"
-         : "volatile");
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn syscall() {
+  use std::arch::asm;
+  harness::bench("syscall", || unsafe {
+    asm!("movq $102, %rax",
+         "syscall",
+         out("rax") _, out("rcx") _, out("r11") _,
+         options(att_syntax, nostack));
   });
 }
 
-#[cfg(target_arch = "x86")]
-#[bench]
-fn syscall(b: &mut test::Bencher) {
-  b.iter(|| unsafe {
-    asm!("mov $$24, %eax\n\
-          int $$0x80"
-         :
-         :
-         : "eax"
-         : "volatile");
+#[cfg(all(target_os = "linux", target_arch = "x86"))]
+fn syscall() {
+  use std::arch::asm;
+  harness::bench("syscall", || unsafe {
+    asm!("movl $24, %eax",
+         "int $0x80",
+         out("eax") _,
+         options(att_syntax, nostack));
   });
 }
+
+fn main() {
+  #[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "x86")))]
+  syscall();
+}
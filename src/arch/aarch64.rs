@@ -20,7 +20,9 @@
 //   to pass a value while swapping context; this is an arbitrary choice
 //   (we clobber all registers and could use any of them) but this allows us
 //   to reuse the swap function to perform the initial call. We do the same
-//   thing with x1 to pass the stack pointer to the new context.
+//   thing with x1 to pass the stack pointer to the new context. Further values
+//   are passed in x5, x6 and x7, which the trampolines do not touch, but only
+//   to a context that is suspended in swap; the initial call receives just x0.
 //
 // To understand the DWARF CFI code in this file, keep in mind these facts:
 // * CFI is "call frame information"; a set of instructions to a debugger or
@@ -42,101 +44,116 @@
 // * The 2nd init trampoline puts a controlled value (written in swap to `new_cfa`)
 //   into x29. This is then used as the CFA for the 1st trampoline.
 // * This controlled value points to the bottom of the stack of the parent context,
-//   which holds the saved x29 and x30 from the call to swap().
-// * The 1st init trampoline tells the unwinder to restore x29 and x30
+//   which holds the saved x29, x30 and x19 from the call to swap().
+// * The 1st init trampoline tells the unwinder to restore x29, x30 and x19
 //   from the stack frame at x29 (in the parent stack), thus continuing
 //   unwinding at the swap call site instead of falling off the end of context stack.
-use core::mem;
+use core::arch::{asm, naked_asm};
+use core::mem::MaybeUninit;
 use stack::Stack;
+use stack_pointer::{Regs, StackPointer, Trampoline};
 
 pub const STACK_ALIGNMENT: usize = 16;
+pub const MIN_STACK_SIZE: usize = 32 * 1024;
+pub const RECOMMENDED_STACK_SIZE: usize = 256 * 1024;
+// Functions with large frames probe every page of them, so a single page catches
+// every overflow.
+pub const MIN_GUARD_SIZE: usize = 4096;
 
-#[derive(Debug, Clone, Copy)]
-pub struct StackPointer(*mut usize);
+/// Number of words passed in registers while swapping context.
+pub const NUM_REGS: usize = 4;
 
-pub unsafe fn init(sp: &mut StackPointer,
-                   f: unsafe extern "C" fn(usize, StackPointer) -> !) {
+/// Offset of the CFA slot from the stack base, in words. Every time a stack
+/// is switched to, the stack pointer of the context that switched to it is
+/// stored there.
+pub const CFA_SLOT: isize = -4;
+
+pub unsafe fn init(sp: &mut StackPointer, f: Trampoline) {
   #[cfg(not(target_vendor = "apple"))]
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_1() {
-    asm!(
+    naked_asm!(
       r#"
-        # gdb has a hardcoded check that rejects backtraces where frame addresses
-        # do not monotonically decrease. It is turned off if the function is called
-        # "__morestack" and that is hardcoded. So, to make gdb backtraces match
-        # the actual unwinder behavior, we call ourselves "__morestack" and mark
-        # the symbol as local; it shouldn't interfere with anything.
+        // gdb has a hardcoded check that rejects backtraces where frame addresses
+        // do not monotonically decrease. It is turned off if the function is called
+        // "__morestack" and that is hardcoded. So, to make gdb backtraces match
+        // the actual unwinder behavior, we call ourselves "__morestack" and mark
+        // the symbol as local; it shouldn't interfere with anything.
       __morestack:
       .local __morestack
 
-        # Set up the first part of our DWARF CFI linking stacks together. When
-        # we reach this function from unwinding, x29 will be pointing at the bottom
-        # of the parent linked stack. This link is set each time swap() is called.
-        # When unwinding the frame corresponding to this function, a DWARF unwinder
-        # will use x29+16 as the next call frame address, restore return address (x30)
-        # from CFA-8 and restore x29 from CFA-16. This mirrors what the second half
-        # of `swap_trampoline` does.
-        .cfi_def_cfa x29, 16
-        .cfi_offset x30, -8
-        .cfi_offset x29, -16
+        // Naked functions do not get an FDE of their own, so open one explicitly.
+        .cfi_startproc simple
+
+        // Set up the first part of our DWARF CFI linking stacks together. When
+        // we reach this function from unwinding, x29 will be pointing at the bottom
+        // of the parent linked stack. This link is set each time swap() is called.
+        // When unwinding the frame corresponding to this function, a DWARF unwinder
+        // will use x29+32 as the next call frame address, restore x19 from CFA-16,
+        // return address (x30) from CFA-24 and x29 from CFA-32. This mirrors what
+        // the second half of `swap_trampoline` does.
+        .cfi_def_cfa x29, 32
+        .cfi_offset x19, -16
+        .cfi_offset x30, -24
+        .cfi_offset x29, -32
 
-        # This nop is here so that the initial swap doesn't return to the start
-        # of the trampoline, which confuses the unwinder since it will look for
-        # frame information in the previous symbol rather than this one. It is
-        # never actually executed.
+        // This nop is here so that the initial swap doesn't return to the start
+        // of the trampoline, which confuses the unwinder since it will look for
+        // frame information in the previous symbol rather than this one. It is
+        // never actually executed.
         nop
 
-      .Lend:
-      .size __morestack, .Lend-__morestack
-      "#
-      : : : : "volatile")
+        .cfi_endproc
+      .Lmorestack_end:
+      .size __morestack, .Lmorestack_end-__morestack
+      "#)
   }
 
   #[cfg(target_vendor = "apple")]
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_1() {
-    asm!(
+    naked_asm!(
       r#"
-      # Identical to the above, except avoids .local/.size that aren't available on Mach-O.
+      // Identical to the above, except avoids .local/.size that aren't available on Mach-O.
       __morestack:
       .private_extern __morestack
-        .cfi_def_cfa x29, 16
-        .cfi_offset x30, -8
-        .cfi_offset x29, -16
+        .cfi_startproc simple
+        .cfi_def_cfa x29, 32
+        .cfi_offset x19, -16
+        .cfi_offset x30, -24
+        .cfi_offset x29, -32
         nop
-      "#
-      : : : : "volatile")
+        .cfi_endproc
+      "#)
   }
 
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_2() {
-    asm!(
+    naked_asm!(
       r#"
-        # Set up the second part of our DWARF CFI.
-        # When unwinding the frame corresponding to this function, a DWARF unwinder
-        # will restore x29 (and thus CFA of the first trampoline) from the stack slot.
-        # This stack slot is updated every time swap() is called to point to the bottom
-        # of the stack of the context switch just switched from.
+        .cfi_startproc simple
+
+        // Set up the second part of our DWARF CFI.
+        // When unwinding the frame corresponding to this function, a DWARF unwinder
+        // will restore x29 (and thus CFA of the first trampoline) from the stack slot.
+        // This stack slot is updated every time swap() is called to point to the bottom
+        // of the stack of the context switch just switched from.
         .cfi_def_cfa x29, 16
         .cfi_offset x30, -8
         .cfi_offset x29, -16
 
-        # This nop is here so that the return address of the swap trampoline
-        # doesn't point to the start of the symbol. This confuses gdb's backtraces,
-        # causing them to think the parent function is trampoline_1 instead of
-        # trampoline_2.
+        // This nop is here so that the return address of the swap trampoline
+        // doesn't point to the start of the symbol. This confuses gdb's backtraces,
+        // causing them to think the parent function is trampoline_1 instead of
+        // trampoline_2.
         nop
 
-        # Call the provided function.
+        // Call the provided function.
         ldr     x2, [sp, #16]
         blr     x2
-      "#
-      : : : : "volatile")
-  }
 
-  unsafe fn push(sp: &mut StackPointer, val: usize) {
-    sp.0 = sp.0.offset(-1);
-    *sp.0 = val
+        .cfi_endproc
+      "#)
   }
 
   // We set up the stack in a somewhat special way so that to the unwinder it
@@ -147,95 +164,101 @@ pub unsafe fn init(sp: &mut StackPointer,
   // followed by the x29 value for that frame. This setup supports unwinding
   // using DWARF CFI as well as the frame pointer-based unwinding used by tools
   // such as perf or dtrace.
-  sp.push(0 as usize); // Padding to ensure the stack is properly aligned
-  sp.push(f as usize); // Function that trampoline_2 should call
+  sp.push(0);                                      // Padding to ensure the stack is properly aligned
+  sp.push(f as usize);                             // Function that trampoline_2 should call
 
   // Call frame for trampoline_2. The CFA slot is updated by swap::trampoline
   // each time a context switch is performed.
-  sp.push(trampoline_1 as usize + 4); // Return after the nop
-  sp.push(0xdeaddeaddead0cfa);        // CFA slot
+  sp.push(trampoline_1 as *const () as usize + 4); // Return after the nop
+  sp.push(0xdeaddeaddead0cfa);                     // CFA slot
 
   // Call frame for swap::trampoline. We set up the x29 value to point to the
   // parent call frame.
   let frame = *sp;
-  sp.push(trampoline_2 as usize + 4); // Entry point, skip initial nop
-  sp.push(frame as usize);            // Pointer to parent call frame
+  sp.push(0);                                      // Padding to ensure the stack is properly aligned
+  sp.push(0);                                      // Initial x19
+  sp.push(trampoline_2 as *const () as usize + 4); // Entry point, skip initial nop
+  sp.push(frame.0 as usize);                       // Pointer to parent call frame
 }
 
 #[inline(always)]
-pub unsafe fn swap(arg: usize, new_sp: StackPointer,
-                   new_stack: Option<&Stack>) -> (usize, StackPointer) {
+pub unsafe fn swap(args: Regs, new_sp: StackPointer,
+                   new_stack: Option<&dyn Stack>) -> (Regs, StackPointer) {
   // Address of the topmost CFA stack slot.
-  let mut dummy: usize = mem::uninitialized();
+  let mut dummy: usize = 0;
   let new_cfa = if let Some(new_stack) = new_stack {
-    (new_stack.base() as *mut usize).offset(-4)
+    (new_stack.base() as *mut usize).offset(CFA_SLOT)
   } else {
     // Just pass a dummy pointer if we aren't linking the stack
     &mut dummy
   };
 
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline() {
-    asm!(
+    naked_asm!(
       r#"
-        # Save the frame pointer and link register; the unwinder uses them to find
-        # the CFA of the caller, and so they have to have the correct value immediately
-        # after the call instruction that invoked the trampoline.
-        stp     x29, x30, [sp, #-16]!
-        .cfi_adjust_cfa_offset 16
+        .cfi_startproc
+
+        // Save the frame pointer and link register; the unwinder uses them to find
+        // the CFA of the caller, and so they have to have the correct value immediately
+        // after the call instruction that invoked the trampoline. LLVM reserves x19
+        // for its own use, so it cannot be marked as clobbered by the inline assembly
+        // statement in swap(); save it here as well.
+        stp     x29, x30, [sp, #-32]!
+        .cfi_adjust_cfa_offset 32
         .cfi_rel_offset x30, 8
         .cfi_rel_offset x29, 0
+        str     x19, [sp, #16]
+        .cfi_rel_offset x19, 16
 
-        # Link the call stacks together by writing the current stack bottom
-        # address to the CFA slot in the new stack.
+        // Link the call stacks together by writing the current stack bottom
+        // address to the CFA slot in the new stack.
         mov     x4, sp
         str     x4, [x3]
 
-        # Pass the stack pointer of the old context to the new one.
+        // Pass the stack pointer of the old context to the new one.
         mov     x1, sp
-        # Load stack pointer of the new context.
+        // Load stack pointer of the new context.
         mov     sp, x2
 
-        # Load frame and instruction pointers of the new context.
-        ldp     x29, x30, [sp], #16
-        .cfi_adjust_cfa_offset -16
+        // Load x19, frame and instruction pointers of the new context.
+        ldr     x19, [sp, #16]
+        ldp     x29, x30, [sp], #32
+        .cfi_adjust_cfa_offset -32
+        .cfi_restore x19
         .cfi_restore x29
         .cfi_restore x30
 
-        # Return into the new context. Use `br` instead of a `ret` to avoid
-        # return address mispredictions.
+        // Return into the new context. Use `br` instead of a `ret` to avoid
+        // return address mispredictions.
         br      x30
-      "#
-      : : : : "volatile")
+
+        .cfi_endproc
+      "#)
   }
 
-  let ret: usize;
+  let mut ret: Regs = [MaybeUninit::uninit(); NUM_REGS];
   let ret_sp: *mut usize;
   asm!(
     r#"
-      # Call the trampoline to switch to the new context.
-      bl      ${2}
-    "#
-    : "={x0}" (ret)
-      "={x1}" (ret_sp)
-    : "s" (trampoline as usize)
-      "{x0}" (arg)
-      "{x2}" (new_sp.0)
-      "{x3}" (new_cfa)
-    :/*x0,   "x1",*/"x2",  "x3",  "x4",  "x5",  "x6",  "x7",
-      "x8",  "x9",  "x10", "x11", "x12", "x13", "x14", "x15",
-      "x16", "x17", "x18", "x19", "x20", "x21", "x22", "x23",
-      "x24", "x25", "x26", "x27", "x28",/*fp,*/ "lr", /*sp,*/
-      "v0",  "v1",  "v2",  "v3",  "v4",  "v5",  "v6",  "v7",
-      "v8",  "v9",  "v10", "v11", "v12", "v13", "v14", "v15",
-      "v16", "v17", "v18", "v19", "v20", "v21", "v22", "v23",
-      "v24", "v25", "v26", "v27", "v28", "v29", "v30", "v31",
-      "cc", "memory"
-      // Ideally, we would set the LLVM "noredzone" attribute on this function
-      // (and it would be propagated to the call site). Unfortunately, rustc
-      // provides no such functionality. Fortunately, by a lucky coincidence,
-      // the "alignstack" LLVM inline assembly option does exactly the same
-      // thing on AArch64.
-    : "volatile", "alignstack");
+      // Call the trampoline to switch to the new context.
+      bl      {trampoline}
+    "#,
+    trampoline = sym trampoline,
+    inout("x0") args[0] => ret[0],
+    inout("x5") args[1] => ret[1],
+    inout("x6") args[2] => ret[2],
+    inout("x7") args[3] => ret[3],
+    lateout("x1") ret_sp,
+    in("x2") new_sp.0,
+    in("x3") new_cfa,
+    // x19 and x29 are reserved by LLVM and are saved by the trampoline;
+    // everything else is clobbered. The asm block is not marked `nostack`,
+    // so the compiler will not keep anything in the red zone across it
+    // and the stack is aligned for a call on entry.
+    out("x20") _, out("x21") _, out("x22") _, out("x23") _,
+    out("x24") _, out("x25") _, out("x26") _, out("x27") _,
+    out("x28") _,
+    clobber_abi("C"));
   (ret, StackPointer(ret_sp))
 }
@@ -15,7 +15,9 @@
 //   while swapping context; this is an arbitrary choice
 //   (we clobber all registers and could use any of them) but this allows us
 //   to reuse the swap function to perform the initial call. We do the same
-//   thing with r4 to pass the stack pointer to the new context.
+//   thing with r4 to pass the stack pointer to the new context. Further values
+//   are passed in r11, r12 and r13, which the trampolines do not touch, but only
+//   to a context that is suspended in swap; the initial call receives just r3.
 //
 // To understand the DWARF CFI code in this file, keep in mind these facts:
 // * CFI is "call frame information"; a set of instructions to a debugger or
@@ -41,17 +43,30 @@
 // * The 1st init trampoline tells the unwinder to restore r2 and r9
 //   from the stack frame at r2 (in the parent stack), thus continuing
 //   unwinding at the swap call site instead of falling off the end of context stack.
-use core::mem;
+use core::arch::{asm, naked_asm};
+use core::mem::MaybeUninit;
 use stack::Stack;
-use stack_pointer::StackPointer;
+use stack_pointer::{Regs, StackPointer, Trampoline};
 
 pub const STACK_ALIGNMENT: usize = 4;
-
-pub unsafe fn init(sp: &mut StackPointer,
-                   f: unsafe extern "C" fn(usize, StackPointer) -> !) {
-  #[naked]
+pub const MIN_STACK_SIZE: usize = 16 * 1024;
+pub const RECOMMENDED_STACK_SIZE: usize = 128 * 1024;
+// There are no stack probes, so a function can skip over a guard that is smaller than
+// its frame.
+pub const MIN_GUARD_SIZE: usize = 64 * 1024;
+
+/// Number of words passed in registers while swapping context.
+pub const NUM_REGS: usize = 4;
+
+/// Offset of the CFA slot from the stack base, in words. Every time a stack
+/// is switched to, the stack pointer of the context that switched to it is
+/// stored there.
+pub const CFA_SLOT: isize = -2;
+
+pub unsafe fn init(sp: &mut StackPointer, f: Trampoline) {
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_1() {
-    asm!(
+    naked_asm!(
       r#"
         # gdb has a hardcoded check that rejects backtraces where frame addresses
         # do not monotonically decrease. It is turned off if the function is called
@@ -61,6 +76,9 @@ pub unsafe fn init(sp: &mut StackPointer,
       __morestack:
       .local __morestack
 
+        # Naked functions do not get an FDE of their own, so open one explicitly.
+        .cfi_startproc simple
+
         # Set up the first part of our DWARF CFI linking stacks together. When
         # we reach this function from unwinding, r2 will be pointing at the bottom
         # of the parent linked stack. This link is set each time swap() is called.
@@ -78,16 +96,18 @@ pub unsafe fn init(sp: &mut StackPointer,
         # never actually executed.
         l.nop
 
-      .Lend:
-      .size __morestack, .Lend-__morestack
-      "#
-      : : : : "volatile")
+        .cfi_endproc
+      .Lmorestack_end:
+      .size __morestack, .Lmorestack_end-__morestack
+      "#)
   }
 
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_2() {
-    asm!(
+    naked_asm!(
       r#"
+        .cfi_startproc simple
+
         # Set up the second part of our DWARF CFI.
         # When unwinding the frame corresponding to this function, a DWARF unwinder
         # will restore r2 (and thus CFA of the first trampoline) from the stack slot.
@@ -107,8 +127,9 @@ pub unsafe fn init(sp: &mut StackPointer,
         l.lwz   r5, 8(r1)
         l.jalr  r5
         l.nop
-      "#
-      : : : : "volatile")
+
+        .cfi_endproc
+      "#)
   }
 
   // We set up the stack in a somewhat special way so that to the unwinder it
@@ -119,39 +140,41 @@ pub unsafe fn init(sp: &mut StackPointer,
   // followed by the r2 value for that frame. This setup supports unwinding
   // using DWARF CFI as well as the frame pointer-based unwinding used by tools
   // such as perf or dtrace.
-  sp.push(f as usize); // Function that trampoline_2 should call
+  sp.push(f as usize);                                     // Function that trampoline_2 should call
 
   // Call frame for trampoline_2. The CFA slot is updated by swap::trampoline
   // each time a context switch is performed.
-  sp.push(0xdead0cfa);                // CFA slot
-  sp.push(trampoline_1 as usize + 4); // Return after the nop
+  sp.push(0xdead0cfa);                                     // CFA slot
+  sp.push(trampoline_1 as *const () as usize + 4);         // Return after the nop
 
   // Call frame for swap::trampoline. We set up the r2 value to point to the
   // parent call frame.
-  let scratch_sp = *sp;
-  scratch_sp.push(sp.0 as usize);          // Pointer to parent call frame
-  scratch_sp.push(trampoline_2 as usize + 4); // Entry point
+  let mut scratch_sp = *sp;
+  scratch_sp.push(sp.0 as usize);                          // Pointer to parent call frame
+  scratch_sp.push(trampoline_2 as *const () as usize + 4); // Entry point
 
   // The last two values are read by the swap trampoline and are actually in the
   // red zone and not below the stack pointer.
 }
 
 #[inline(always)]
-pub unsafe fn swap(arg: usize, new_sp: StackPointer,
-                   new_stack: Option<&Stack>) -> (usize, StackPointer) {
+pub unsafe fn swap(args: Regs, new_sp: StackPointer,
+                   new_stack: Option<&dyn Stack>) -> (Regs, StackPointer) {
   // Address of the topmost CFA stack slot.
-  let mut dummy: usize = mem::uninitialized();
+  let mut dummy: usize = 0;
   let new_cfa = if let Some(new_stack) = new_stack {
-    (new_stack.base() as *mut usize).offset(-2)
+    (new_stack.base() as *mut usize).offset(CFA_SLOT)
   } else {
     // Just pass a dummy pointer if we aren't linking the stack
     &mut dummy
   };
 
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline() {
-    asm!(
+    naked_asm!(
       r#"
+        .cfi_startproc
+
         # Save the frame pointer and link register; the unwinder uses them to find
         # the CFA of the caller, and so they have to have the correct value immediately
         # after the call instruction that invoked the trampoline.
@@ -178,29 +201,34 @@ pub unsafe fn swap(arg: usize, new_sp: StackPointer,
         # Return into the new context.
         l.jr    r9
         l.nop
-      "#
-      : : : : "volatile")
+
+        .cfi_endproc
+      "#)
   }
 
-  let ret: usize;
+  let mut ret: Regs = [MaybeUninit::uninit(); NUM_REGS];
   let ret_sp: *mut usize;
   asm!(
     r#"
       # Call the trampoline to switch to the new context.
-      l.jal   ${2}
+      l.jal   {trampoline}
       l.nop
-    "#
-    : "={r3}" (ret)
-      "={r4}" (ret_sp)
-    : "s" (trampoline as usize)
-      "{r3}" (arg)
-      "{r5}" (new_sp.0)
-      "{r6}" (new_cfa)
-    :/*"r0", "r1",  "r2",  "r3",  "r4",*/"r5",  "r6",  "r7",
-      "r8",  "r9",  "r10", "r11", "r12", "r13", "r14", "r15",
-      "r16", "r17", "r18", "r19", "r20", "r21", "r22", "r23",
-      "r24", "r25", "r26", "r27", "r28", "r29", "r30", "r31",
-      "cc", "memory"
-    : "volatile");
+    "#,
+    trampoline = sym trampoline,
+    inout("r3")  args[0] => ret[0],
+    inout("r11") args[1] => ret[1],
+    inout("r12") args[2] => ret[2],
+    inout("r13") args[3] => ret[3],
+    lateout("r4") ret_sp,
+    inout("r5") new_sp.0 => _,
+    inout("r6") new_cfa => _,
+    // r1 and r2 are the stack and frame pointers and are saved by
+    // the trampoline; everything else is clobbered.
+    out("r7") _,  out("r8") _,  out("r9") _,  out("r10") _,
+    out("r14") _, out("r15") _, out("r16") _, out("r17") _,
+    out("r18") _, out("r19") _, out("r20") _, out("r21") _,
+    out("r22") _, out("r23") _, out("r24") _, out("r25") _,
+    out("r26") _, out("r27") _, out("r28") _, out("r29") _,
+    out("r30") _, out("r31") _);
   (ret, StackPointer(ret_sp))
 }
@@ -15,7 +15,9 @@
 //   a function has to realign the stack from an unknown state.
 // * i686 SysV C ABI passes the first argument on the stack. This is
 //   unfortunate, because unlike every other architecture we can't reuse
-//   `swap` for the initial call, and so we use a trampoline.
+//   `swap` for the initial call, and so we use a trampoline. We pass a value
+//   in %edi while swapping context, and another one in %eax, but only to
+//   a context that is suspended in swap; the initial call receives just %edi.
 //
 // To understand the DWARF CFI code in this file, keep in mind these facts:
 // * CFI is "call frame information"; a set of instructions to a debugger or
@@ -37,22 +39,35 @@
 // * The 2nd init trampoline puts a controlled value (written in swap to `new_cfa`)
 //   into %ebp. This is then used as the CFA for the 1st trampoline.
 // * This controlled value points to the bottom of the stack of the parent context,
-//   which holds the saved %ebp and return address from the call to swap().
-// * The 1st init trampoline tells the unwinder to restore %ebp and its return
+//   which holds the saved %ebp, %esi and return address from the call to swap().
+// * The 1st init trampoline tells the unwinder to restore %ebp, %esi and its return
 //   address from the stack frame at %ebp (in the parent stack), thus continuing
 //   unwinding at the swap call site instead of falling off the end of context stack.
-use core::mem;
+use core::arch::{asm, naked_asm};
+use core::mem::MaybeUninit;
 use stack::Stack;
-use stack_pointer::StackPointer;
+use stack_pointer::{Regs, StackPointer, Trampoline};
 
 pub const STACK_ALIGNMENT: usize = 16;
+pub const MIN_STACK_SIZE: usize = 16 * 1024;
+pub const RECOMMENDED_STACK_SIZE: usize = 128 * 1024;
+// Functions with large frames probe every page of them, so a single page catches
+// every overflow.
+pub const MIN_GUARD_SIZE: usize = 4096;
 
-pub unsafe fn init(sp: &mut StackPointer,
-                   f: unsafe extern "C" fn(usize, StackPointer) -> !) {
+/// Number of words passed in registers while swapping context.
+pub const NUM_REGS: usize = 2;
+
+/// Offset of the CFA slot from the stack base, in words. Every time a stack
+/// is switched to, the stack pointer of the context that switched to it is
+/// stored there.
+pub const CFA_SLOT: isize = -6;
+
+pub unsafe fn init(sp: &mut StackPointer, f: Trampoline) {
   #[cfg(not(target_vendor = "apple"))]
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_1() {
-    asm!(
+    naked_asm!(
       r#"
         # gdb has a hardcoded check that rejects backtraces where frame addresses
         # do not monotonically decrease. It is turned off if the function is called
@@ -62,15 +77,20 @@ pub unsafe fn init(sp: &mut StackPointer,
       __morestack:
       .local __morestack
 
+        # Naked functions do not get an FDE of their own, so open one explicitly.
+        .cfi_startproc simple
+
         # Set up the first part of our DWARF CFI linking stacks together. When
         # we reach this function from unwinding, %ebp will be pointing at the bottom
         # of the parent linked stack. This link is set each time swap() is called.
         # When unwinding the frame corresponding to this function, a DWARF unwinder
-        # will use %ebp+8 as the next call frame address, restore return address
-        # from CFA-4 and restore %ebp from CFA-8. This mirrors what the second half
-        # of `swap_trampoline` does.
-        .cfi_def_cfa %ebp, 8
-        .cfi_offset %ebp, -8
+        # will use %ebp+12 as the next call frame address, restore return address
+        # from CFA-4, %esi from CFA-8 and %ebp from CFA-12. This mirrors what
+        # the second half of `swap_trampoline` does.
+        .cfi_def_cfa %ebp, 12
+        .cfi_offset %eip, -4
+        .cfi_offset %esi, -8
+        .cfi_offset %ebp, -12
 
         # This nop is here so that the initial swap doesn't return to the start
         # of the trampoline, which confuses the unwinder since it will look for
@@ -83,38 +103,46 @@ pub unsafe fn init(sp: &mut StackPointer,
         # executed either, it is only here to pad the symbol size.
         nop
 
-      .Lend:
-      .size __morestack, .Lend-__morestack
-      "#
-      : : : : "volatile")
+        .cfi_endproc
+      .Lmorestack_end:
+      .size __morestack, .Lmorestack_end-__morestack
+      "#,
+      options(att_syntax))
   }
 
   #[cfg(target_vendor = "apple")]
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_1() {
-    asm!(
+    naked_asm!(
       r#"
       # Identical to the above, except avoids .local/.size that aren't available on Mach-O.
       __morestack:
       .private_extern __morestack
-        .cfi_def_cfa %ebp, 8
-        .cfi_offset %ebp, -8
+        .cfi_startproc simple
+        .cfi_def_cfa %ebp, 12
+        .cfi_offset %eip, -4
+        .cfi_offset %esi, -8
+        .cfi_offset %ebp, -12
         nop
         nop
-      "#
-      : : : : "volatile")
+        .cfi_endproc
+      "#,
+      options(att_syntax))
   }
 
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_2() {
-    asm!(
+    naked_asm!(
       r#"
+        .cfi_startproc simple
+
         # Set up the second part of our DWARF CFI.
         # When unwinding the frame corresponding to this function, a DWARF unwinder
         # will restore %ebp (and thus CFA of the first trampoline) from the stack slot.
         # This stack slot is updated every time swap() is called to point to the bottom
         # of the stack of the context switch just switched from.
         .cfi_def_cfa %ebp, 8
+        .cfi_offset %eip, -4
         .cfi_offset %ebp, -8
 
         # This nop is here so that the return address of the swap trampoline
@@ -124,12 +152,14 @@ pub unsafe fn init(sp: &mut StackPointer,
         nop
 
         # Push arguments.
-        pushl   %esi
+        pushl   %ebx
         pushl   %edi
         # Call the provided function.
-        calll  *16(%esp)
-      "#
-      : : : : "volatile")
+        calll   *16(%esp)
+
+        .cfi_endproc
+      "#,
+      options(att_syntax))
   }
 
   // We set up the stack in a somewhat special way so that to the unwinder it
@@ -140,39 +170,48 @@ pub unsafe fn init(sp: &mut StackPointer,
   // followed by the %ebp value for that frame. This setup supports unwinding
   // using DWARF CFI as well as the frame pointer-based unwinding used by tools
   // such as perf or dtrace.
-  sp.push(0 as usize); // Padding to ensure the stack is properly aligned
-  sp.push(0 as usize); // Padding to ensure the stack is properly aligned
-  sp.push(0 as usize); // Padding to ensure the stack is properly aligned
-  sp.push(f as usize); // Function that trampoline_2 should call
+  sp.push(0);                                      // Padding to ensure the stack is properly aligned
+  sp.push(0);                                      // Padding to ensure the stack is properly aligned
+  sp.push(0);                                      // Padding to ensure the stack is properly aligned
+  sp.push(f as usize);                             // Function that trampoline_2 should call
 
   // Call frame for trampoline_2. The CFA slot is updated by swap::trampoline
   // each time a context switch is performed.
-  sp.push(trampoline_1 as usize + 2); // Return after the 2 nops
-  sp.push(0xdead0cfa);                // CFA slot
+  sp.push(trampoline_1 as *const () as usize + 2); // Return after the 2 nops
+  sp.push(0xdead0cfa);                             // CFA slot
 
   // Call frame for swap::trampoline. We set up the %ebp value to point to the
   // parent call frame.
   let frame = *sp;
-  sp.push(trampoline_2 as usize + 1); // Entry point
-  sp.push(frame.0 as usize);          // Pointer to parent call frame
+  sp.push(trampoline_2 as *const () as usize + 1); // Entry point
+  sp.push(0);                                      // Initial %esi
+  sp.push(frame.0 as usize);                       // Pointer to parent call frame
 }
 
 #[inline(always)]
-pub unsafe fn swap(arg: usize, new_sp: StackPointer,
-                   new_stack: Option<&Stack>) -> (usize, StackPointer) {
+pub unsafe fn swap(args: Regs, new_sp: StackPointer,
+                   new_stack: Option<&dyn Stack>) -> (Regs, StackPointer) {
   // Address of the topmost CFA stack slot.
-  let mut dummy: usize = mem::uninitialized();
+  let mut dummy: usize = 0;
   let new_cfa = if let Some(new_stack) = new_stack {
-    (new_stack.base() as *mut usize).offset(-6)
+    (new_stack.base() as *mut usize).offset(CFA_SLOT)
   } else {
     // Just pass a dummy pointer if we aren't linking the stack
     &mut dummy
   };
 
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline() {
-    asm!(
+    naked_asm!(
       r#"
+        .cfi_startproc
+
+        # LLVM reserves %esi for its own use, so it cannot be marked as clobbered
+        # by the inline assembly statement in swap(); save it here instead.
+        pushl   %esi
+        .cfi_adjust_cfa_offset 4
+        .cfi_rel_offset %esi, 0
+
         # Save frame pointer explicitly; the unwinder uses it to find CFA of
         # the caller, and so it has to have the correct value immediately after
         # the call instruction that invoked the trampoline.
@@ -185,43 +224,49 @@ pub unsafe fn swap(arg: usize, new_sp: StackPointer,
         movl    %esp, (%ecx)
 
         # Pass the stack pointer of the old context to the new one.
-        movl    %esp, %esi
+        movl    %esp, %ebx
         # Load stack pointer of the new context.
         movl    %edx, %esp
 
-        # Restore frame pointer of the new context.
+        # Restore frame pointer and %esi of the new context.
         popl    %ebp
         .cfi_adjust_cfa_offset -4
         .cfi_restore %ebp
+        popl    %esi
+        .cfi_adjust_cfa_offset -4
+        .cfi_restore %esi
 
         # Return into the new context. Use `pop` and `jmp` instead of a `ret`
         # to avoid return address mispredictions (~8ns per `ret` on Ivy Bridge).
-        popl    %eax
+        # %eax carries a value, so jump through %ecx, which is no longer needed.
+        popl    %ecx
         .cfi_adjust_cfa_offset -4
-        .cfi_register %eip, %eax
-        jmpl    *%eax
-      "#
-      : : : : "volatile")
+        .cfi_register %eip, %ecx
+        jmpl    *%ecx
+
+        .cfi_endproc
+      "#,
+      options(att_syntax))
   }
 
-  let ret: usize;
+  let mut ret: Regs = [MaybeUninit::uninit(); NUM_REGS];
   let ret_sp: *mut usize;
   asm!(
     r#"
       # Push instruction pointer of the old context and switch to
       # the new context.
-      call    ${2:c}
-    "#
-    : "={edi}" (ret)
-      "={esi}" (ret_sp)
-    : "s" (trampoline as usize)
-      "{edi}" (arg)
-      "{edx}" (new_sp.0)
-      "{ecx}" (new_cfa)
-    : "eax", "ebx", "ecx",  "edx", /*"esi",  "edi", "ebp",  "esp",*/
-      "mm0",  "mm1",  "mm2",  "mm3",  "mm4",  "mm5",  "mm6",  "mm7",
-      "xmm0", "xmm1", "xmm2", "xmm3", "xmm4", "xmm5", "xmm6", "xmm7",
-      "cc", "dirflag", "fpsr", "flags", "memory"
-    : "volatile");
+      call    {trampoline}
+    "#,
+    trampoline = sym trampoline,
+    inout("edi") args[0] => ret[0],
+    inout("eax") args[1] => ret[1],
+    lateout("ebx") ret_sp,
+    in("edx") new_sp.0,
+    in("ecx") new_cfa,
+    // %esi and %ebp are reserved by LLVM and are saved by the trampoline;
+    // everything else is clobbered. The asm block is not marked `nostack`,
+    // so the stack is aligned for a call on entry.
+    clobber_abi("C"),
+    options(att_syntax));
   (ret, StackPointer(ret_sp))
 }
@@ -20,7 +20,9 @@
 //   to pass a value while swapping context; this is an arbitrary choice
 //   (we clobber all registers and could use any of them) but this allows us
 //   to reuse the swap function to perform the initial call. We do the same
-//   thing with %rsi to pass the stack pointer to the new context.
+//   thing with %rsi to pass the stack pointer to the new context. Further values
+//   are passed in %r8, %r9 and %r10, which the trampolines do not touch, but only
+//   to a context that is suspended in swap; the initial call receives just %rdi.
 //
 // To understand the DWARF CFI code in this file, keep in mind these facts:
 // * CFI is "call frame information"; a set of instructions to a debugger or
@@ -42,22 +44,35 @@
 // * The 2nd init trampoline puts a controlled value (written in swap to `new_cfa`)
 //   into %rbp. This is then used as the CFA for the 1st trampoline.
 // * This controlled value points to the bottom of the stack of the parent context,
-//   which holds the saved %rbp and return address from the call to swap().
-// * The 1st init trampoline tells the unwinder to restore %rbp and its return
+//   which holds the saved %rbp, %rbx and return address from the call to swap().
+// * The 1st init trampoline tells the unwinder to restore %rbp, %rbx and its return
 //   address from the stack frame at %rbp (in the parent stack), thus continuing
 //   unwinding at the swap call site instead of falling off the end of context stack.
-use core::mem;
+use core::arch::{asm, naked_asm};
+use core::mem::MaybeUninit;
 use stack::Stack;
-use stack_pointer::StackPointer;
+use stack_pointer::{Regs, StackPointer, Trampoline};
 
 pub const STACK_ALIGNMENT: usize = 16;
+pub const MIN_STACK_SIZE: usize = 32 * 1024;
+pub const RECOMMENDED_STACK_SIZE: usize = 256 * 1024;
+// Functions with large frames probe every page of them, so a single page catches
+// every overflow.
+pub const MIN_GUARD_SIZE: usize = 4096;
 
-pub unsafe fn init(sp: &mut StackPointer,
-                   f: unsafe extern "C" fn(usize, StackPointer) -> !) {
+/// Number of words passed in registers while swapping context.
+pub const NUM_REGS: usize = 4;
+
+/// Offset of the CFA slot from the stack base, in words. Every time a stack
+/// is switched to, the stack pointer of the context that switched to it is
+/// stored there.
+pub const CFA_SLOT: isize = -4;
+
+pub unsafe fn init(sp: &mut StackPointer, f: Trampoline) {
   #[cfg(not(target_vendor = "apple"))]
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_1() {
-    asm!(
+    naked_asm!(
       r#"
         # gdb has a hardcoded check that rejects backtraces where frame addresses
         # do not monotonically decrease. It is turned off if the function is called
@@ -67,15 +82,20 @@ pub unsafe fn init(sp: &mut StackPointer,
       __morestack:
       .local __morestack
 
+        # Naked functions do not get an FDE of their own, so open one explicitly.
+        .cfi_startproc simple
+
         # Set up the first part of our DWARF CFI linking stacks together. When
         # we reach this function from unwinding, %rbp will be pointing at the bottom
         # of the parent linked stack. This link is set each time swap() is called.
         # When unwinding the frame corresponding to this function, a DWARF unwinder
-        # will use %rbp+16 as the next call frame address, restore return address
-        # from CFA-8 and restore %rbp from CFA-16. This mirrors what the second half
-        # of `swap_trampoline` does.
-        .cfi_def_cfa %rbp, 16
-        .cfi_offset %rbp, -16
+        # will use %rbp+24 as the next call frame address, restore return address
+        # from CFA-8, %rbx from CFA-16 and %rbp from CFA-24. This mirrors what
+        # the second half of `swap_trampoline` does.
+        .cfi_def_cfa %rbp, 24
+        .cfi_offset %rip, -8
+        .cfi_offset %rbx, -16
+        .cfi_offset %rbp, -24
 
         # This nop is here so that the initial swap doesn't return to the start
         # of the trampoline, which confuses the unwinder since it will look for
@@ -88,38 +108,46 @@ pub unsafe fn init(sp: &mut StackPointer,
         # executed either, it is only here to pad the symbol size.
         nop
 
-      .Lend:
-      .size __morestack, .Lend-__morestack
-      "#
-      : : : : "volatile")
+        .cfi_endproc
+      .Lmorestack_end:
+      .size __morestack, .Lmorestack_end-__morestack
+      "#,
+      options(att_syntax))
   }
 
   #[cfg(target_vendor = "apple")]
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_1() {
-    asm!(
+    naked_asm!(
       r#"
       # Identical to the above, except avoids .local/.size that aren't available on Mach-O.
       __morestack:
       .private_extern __morestack
-        .cfi_def_cfa %rbp, 16
-        .cfi_offset %rbp, -16
+        .cfi_startproc simple
+        .cfi_def_cfa %rbp, 24
+        .cfi_offset %rip, -8
+        .cfi_offset %rbx, -16
+        .cfi_offset %rbp, -24
         nop
         nop
-      "#
-      : : : : "volatile")
+        .cfi_endproc
+      "#,
+      options(att_syntax))
   }
 
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline_2() {
-    asm!(
+    naked_asm!(
       r#"
+        .cfi_startproc simple
+
         # Set up the second part of our DWARF CFI.
         # When unwinding the frame corresponding to this function, a DWARF unwinder
         # will restore %rbp (and thus CFA of the first trampoline) from the stack slot.
         # This stack slot is updated every time swap() is called to point to the bottom
         # of the stack of the context switch just switched from.
         .cfi_def_cfa %rbp, 16
+        .cfi_offset %rip, -8
         .cfi_offset %rbp, -16
 
         # This nop is here so that the return address of the swap trampoline
@@ -130,8 +158,10 @@ pub unsafe fn init(sp: &mut StackPointer,
 
         # Call the provided function.
         call    *16(%rsp)
-      "#
-      : : : : "volatile")
+
+        .cfi_endproc
+      "#,
+      options(att_syntax))
   }
 
   // We set up the stack in a somewhat special way so that to the unwinder it
@@ -143,37 +173,46 @@ pub unsafe fn init(sp: &mut StackPointer,
   // using DWARF CFI as well as the frame pointer-based unwinding used by tools
   // such as perf or dtrace.
 
-  sp.push(0 as usize); // Padding to ensure the stack is properly aligned
-  sp.push(f as usize); // Function that trampoline_2 should call
+  sp.push(0);                                      // Padding to ensure the stack is properly aligned
+  sp.push(f as usize);                             // Function that trampoline_2 should call
 
   // Call frame for trampoline_2. The CFA slot is updated by swap::trampoline
   // each time a context switch is performed.
-  sp.push(trampoline_1 as usize + 2); // Return after the 2 nops
-  sp.push(0xdeaddeaddead0cfa);        // CFA slot
+  sp.push(trampoline_1 as *const () as usize + 2); // Return after the 2 nops
+  sp.push(0xdeaddeaddead0cfa);                     // CFA slot
 
   // Call frame for swap::trampoline. We set up the %rbp value to point to the
   // parent call frame.
   let frame = *sp;
-  sp.push(trampoline_2 as usize + 1); // Entry point
-  sp.push(frame.0 as usize);          // Pointer to parent call frame
+  sp.push(trampoline_2 as *const () as usize + 1); // Entry point
+  sp.push(0);                                      // Initial %rbx
+  sp.push(frame.0 as usize);                       // Pointer to parent call frame
 }
 
 #[inline(always)]
-pub unsafe fn swap(arg: usize, new_sp: StackPointer,
-                   new_stack: Option<&Stack>) -> (usize, StackPointer) {
+pub unsafe fn swap(args: Regs, new_sp: StackPointer,
+                   new_stack: Option<&dyn Stack>) -> (Regs, StackPointer) {
   // Address of the topmost CFA stack slot.
-  let mut dummy: usize = mem::uninitialized();
+  let mut dummy: usize = 0;
   let new_cfa = if let Some(new_stack) = new_stack {
-    (new_stack.base() as *mut usize).offset(-4)
+    (new_stack.base() as *mut usize).offset(CFA_SLOT)
   } else {
     // Just pass a dummy pointer if we aren't linking the stack
     &mut dummy
   };
 
-  #[naked]
+  #[unsafe(naked)]
   unsafe extern "C" fn trampoline() {
-    asm!(
+    naked_asm!(
       r#"
+        .cfi_startproc
+
+        # LLVM reserves %rbx for its own use, so it cannot be marked as clobbered
+        # by the inline assembly statement in swap(); save it here instead.
+        pushq   %rbx
+        .cfi_adjust_cfa_offset 8
+        .cfi_rel_offset %rbx, 0
+
         # Save frame pointer explicitly; the unwinder uses it to find CFA of
         # the caller, and so it has to have the correct value immediately after
         # the call instruction that invoked the trampoline.
@@ -190,10 +229,13 @@ pub unsafe fn swap(arg: usize, new_sp: StackPointer,
         # Load stack pointer of the new context.
         movq    %rdx, %rsp
 
-        # Restore frame pointer of the new context.
+        # Restore frame pointer and %rbx of the new context.
         popq    %rbp
         .cfi_adjust_cfa_offset -8
         .cfi_restore %rbp
+        popq    %rbx
+        .cfi_adjust_cfa_offset -8
+        .cfi_restore %rbx
 
         # Return into the new context. Use `pop` and `jmp` instead of a `ret`
         # to avoid return address mispredictions (~8ns per `ret` on Ivy Bridge).
@@ -201,37 +243,34 @@ pub unsafe fn swap(arg: usize, new_sp: StackPointer,
         .cfi_adjust_cfa_offset -8
         .cfi_register %rip, %rax
         jmpq    *%rax
-      "#
-      : : : : "volatile")
+
+        .cfi_endproc
+      "#,
+      options(att_syntax))
   }
 
-  let ret: usize;
+  let mut ret: Regs = [MaybeUninit::uninit(); NUM_REGS];
   let ret_sp: *mut usize;
   asm!(
     r#"
       # Push instruction pointer of the old context and switch to
       # the new context.
-      call    ${2:c}
-    "#
-    : "={rdi}" (ret)
-      "={rsi}" (ret_sp)
-    : "s" (trampoline as usize)
-      "{rdi}" (arg)
-      "{rdx}" (new_sp.0)
-      "{rcx}" (new_cfa)
-    : "rax",   "rbx",   "rcx",   "rdx", /*"rsi",   "rdi",   "rbp",   "rsp",*/
-      "r8",    "r9",    "r10",   "r11",   "r12",   "r13",   "r14",   "r15",
-      "mm0",   "mm1",   "mm2",   "mm3",   "mm4",   "mm5",   "mm6",   "mm7",
-      "xmm0",  "xmm1",  "xmm2",  "xmm3",  "xmm4",  "xmm5",  "xmm6",  "xmm7",
-      "xmm8",  "xmm9",  "xmm10", "xmm11", "xmm12", "xmm13", "xmm14", "xmm15",
-      "xmm16", "xmm17", "xmm18", "xmm19", "xmm20", "xmm21", "xmm22", "xmm23",
-      "xmm24", "xmm25", "xmm26", "xmm27", "xmm28", "xmm29", "xmm30", "xmm31",
-      "cc", "dirflag", "fpsr", "flags", "memory"
-      // Ideally, we would set the LLVM "noredzone" attribute on this function
-      // (and it would be propagated to the call site). Unfortunately, rustc
-      // provides no such functionality. Fortunately, by a lucky coincidence,
-      // the "alignstack" LLVM inline assembly option does exactly the same
-      // thing on x86_64.
-    : "volatile", "alignstack");
+      call    {trampoline}
+    "#,
+    trampoline = sym trampoline,
+    inout("rdi") args[0] => ret[0],
+    inout("r8")  args[1] => ret[1],
+    inout("r9")  args[2] => ret[2],
+    inout("r10") args[3] => ret[3],
+    lateout("rsi") ret_sp,
+    in("rdx") new_sp.0,
+    in("rcx") new_cfa,
+    // %rbx and %rbp are reserved by LLVM and are saved by the trampoline;
+    // everything else is clobbered. The asm block is not marked `nostack`,
+    // so the compiler will not keep anything in the red zone across it
+    // and the stack is aligned for a call on entry.
+    out("r12") _, out("r13") _, out("r14") _, out("r15") _,
+    clobber_abi("C"),
+    options(att_syntax));
   (ret, StackPointer(ret_sp))
 }
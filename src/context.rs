@@ -0,0 +1,188 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Low-level context switching.
+//!
+//! A [`Context`](struct.Context.html) is a suspended execution context: either one
+//! that was created on a stack with `Context::new` and has not run yet, or one that
+//! has switched to another context and can be switched back to. Switching consumes
+//! the context being switched to and produces the context that was switched from,
+//! so contexts can switch to each other in any order, not just in the resumer/generator
+//! fashion of [`Generator`](../generator/struct.Generator.html).
+//!
+//! The call stacks of contexts are linked together the same way as those of generators,
+//! so debuggers and profilers see a context's call stack continue into the one that has
+//! last switched to it. Every switch passes along the base of the stack it is made from,
+//! so that switching back relinks it; this requires the `std` feature, without which only
+//! the first switch to a context links its call stack. Switching is unsafe, since nothing
+//! keeps track of which contexts exist, and which values they expect.
+//!
+//! # Example
+//!
+//! ```
+//! use fringe::OsStack;
+//! use fringe::context::Context;
+//!
+//! let stack = OsStack::new(0).unwrap();
+//! unsafe {
+//!   let context = Context::new(&stack, |mut caller: Context, mut value: usize| {
+//!     while value < 4 { (caller, value) = caller.switch(value * 2) }
+//!     (caller, 0usize)
+//!   });
+//!   let (context, value) = context.switch(1);
+//!   println!("{}", value); // prints 2
+//!   let (context, value) = context.switch(value);
+//!   println!("{}", value); // prints 4
+//!   let (_, value) = context.switch(value);
+//!   println!("{}", value); // prints 0
+//! }
+//! ```
+
+#[cfg(feature = "std")]
+use core::cell::Cell;
+
+use stack::Stack;
+use stack_pointer::StackPointer;
+use fat_args;
+#[cfg(feature = "std")]
+use implicit;
+
+#[cfg(feature = "std")]
+thread_local! {
+  /// The base of the stack of the context running on the current thread, if it was
+  /// created with `new`, or 0.
+  static BASE: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Records that the context with stack base `base` is about to run on the current thread,
+/// and returns the base of the one that was running, so that it can be restored once
+/// control comes back.
+#[cfg(feature = "std")]
+#[inline(always)]
+pub(crate) fn enter(base: usize) -> usize {
+  BASE.with(|current| current.replace(base))
+}
+
+#[cfg(not(feature = "std"))]
+#[inline(always)]
+pub(crate) fn enter(_base: usize) -> usize {
+  0
+}
+
+/// A suspended execution context; see the [module documentation](index.html).
+#[derive(Debug)]
+pub struct Context {
+  stack_ptr: StackPointer,
+  /// The base of the stack of the context, if it was created with `new`, or 0.
+  base:      usize
+}
+
+/// The stack of a context, which is only used to link call stacks together.
+struct Link(usize);
+
+impl Stack for Link {
+  fn base(&self) -> *mut u8 { self.0 as *mut u8 }
+  fn limit(&self) -> *mut u8 { self.0 as *mut u8 }
+}
+
+impl Context {
+  /// Creates a new context that runs `f` on `stack` once it is switched to.
+  /// `f` receives the context that has switched to it and the value passed to `switch`.
+  /// When `f` returns `(context, value)`, the new context finishes and switches
+  /// to `context`, passing it `value`.
+  ///
+  /// # Safety
+  ///
+  /// `stack` must outlive every use of the context and of any context derived
+  /// from it by switching. A context that has finished must not be switched to again.
+  /// `f` must never unwind; a panic that escapes it aborts the process. `f` can easily
+  /// violate memory safety by overflowing `stack`, which has to have a guard page
+  /// or be known to be large enough.
+  ///
+  /// `f` and everything it captures live on `stack` until `f` is called; if the context
+  /// is never switched to, they are leaked rather than dropped.
+  ///
+  /// See also the [contract](../trait.Stack.html) that needs to be fulfilled by `stack`.
+  pub unsafe fn new<I, O, F>(stack: &dyn Stack, f: F) -> Context
+      where F: FnOnce(Context, I) -> (Context, O) {
+    let init = fat_args::init0(stack);
+    let (stack_ptr, ()) = fat_args::init1(init, Some(stack), move |creator_sp| {
+      // Return control to the creator, and wait to be switched to.
+      let (switcher_sp, (arg, base)) = fat_args::swap::<(), (I, usize)>((), creator_sp, None);
+      let (next, value) = f(Context { stack_ptr: switcher_sp, base }, arg);
+      let (_, ()) = next.switch_with(value);
+    });
+    Context { stack_ptr, base: stack.base() as usize }
+  }
+
+  /// Returns the context, with its call stack linked to the one of whichever context
+  /// switches to it, as if it was created with `new` on a stack with base `base`.
+  #[inline(always)]
+  pub(crate) unsafe fn linked(self, base: usize) -> Context {
+    Context { base, ..self }
+  }
+
+  /// Switches to the context, passing it `arg`. Returns once another context switches
+  /// back to the current one, with that context and the value it has passed.
+  ///
+  /// # Safety
+  ///
+  /// The context must be waiting for a `usize`, i.e. have been created with
+  /// `new::<usize, _, _>` and not switched to yet, or be suspended in `switch`.
+  /// Its stack must still be alive.
+  #[inline(always)]
+  pub unsafe fn switch(self, arg: usize) -> (Context, usize) {
+    self.switch_with(arg)
+  }
+
+  /// Same as `switch`, but passes a value of any type `I`, and returns a value of
+  /// type `O`. Values that fit in a few registers are passed in them.
+  ///
+  /// # Safety
+  ///
+  /// The context must be waiting for an `I`, i.e. have been created with `new::<I, _, _>`
+  /// and not switched to yet, or be suspended in `switch_with::<_, I>`, and the context
+  /// that switches back to the current one must pass an `O`. Its stack must still be alive.
+  #[inline(always)]
+  pub unsafe fn switch_with<I, O>(self, arg: I) -> (Context, O) {
+    let link = Link(self.base);
+    let new_stack = if self.base != 0 { Some(&link as &dyn Stack) } else { None };
+    #[cfg(feature = "std")]
+    let current = implicit::leave();
+    let own_base = enter(self.base);
+    let (stack_ptr, (value, base)) = fat_args::swap((arg, own_base), self.stack_ptr, new_stack);
+    enter(own_base);
+    #[cfg(feature = "std")]
+    implicit::restore(current);
+    (Context { stack_ptr, base }, value)
+  }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+  use stack::Stack;
+  use OsStack;
+
+  use super::Context;
+
+  #[test]
+  fn switch_passes_base() {
+    let stack = OsStack::new(0).unwrap();
+    unsafe {
+      let context = Context::new(&stack, |caller: Context, ()| {
+        let base = caller.base;
+        let (caller, ()) = caller.switch_with(base);
+        (caller, 0usize)
+      });
+      let (context, base): (Context, usize) = context.switch_with(());
+      assert_eq!(base, 0);
+      assert_eq!(context.base, stack.base() as usize);
+      let (_, base): (Context, usize) = context.switch_with(());
+      assert_eq!(base, 0);
+    }
+  }
+}
@@ -0,0 +1,412 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Symmetric coroutines.
+//!
+//! Unlike a generator, which always returns control to whoever has resumed it,
+//! a coroutine can transfer control directly to any other suspended coroutine.
+
+use core::marker::PhantomData;
+use core::{fmt, mem, ptr};
+use core::cell::Cell;
+
+#[cfg(feature = "std")]
+use std::any::Any;
+#[cfg(feature = "std")]
+use std::boxed::Box;
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe};
+
+use stack::{self, StackError};
+use debug;
+use context::Context;
+use generator::{Frame, env_footprint};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+  /// Coroutine can be resumed or transferred to. This is the initial state.
+  Suspended,
+  /// Coroutine is running, or has transferred control to another coroutine that
+  /// has not suspended yet.
+  Running,
+  /// Coroutine cannot be switched to. This is the state of the coroutine after
+  /// the coroutine function has returned or has been cancelled.
+  Finished,
+  /// Coroutine cannot be switched to. This is the state of the coroutine after
+  /// the coroutine function has panicked.
+  Panicked
+}
+
+/// Coroutine is a function running on its own stack that can hand control over
+/// to other coroutines, of the same value type `T`, without going through the caller.
+///
+/// `resume()` switches to a suspended coroutine, which runs until it or a coroutine
+/// it has transferred control to calls `Current::suspend()` or returns; the value
+/// passed there is returned from `resume()`. Meanwhile, `Current::transfer()` switches
+/// from the running coroutine directly to another suspended one, and returns once
+/// any coroutine transfers control back.
+///
+/// A coroutine that has returned or panicked cannot be switched to again. If the
+/// coroutine function panics, the panic is propagated through the `resume()` call
+/// that has started the chain of transfers. (Without the `std` feature, it aborts.)
+///
+/// If the coroutine is dropped while the coroutine function is suspended, it is resumed
+/// one last time and the pending `transfer()` or `suspend()` call unwinds its stack,
+/// running the destructors of every value it holds. (This requires the `std` feature;
+/// without it, such values are leaked.) A coroutine created with `unsafe_new` leaks them
+/// as well, since its stack may be too small for unwinding and has no guard page to catch
+/// the overflow. Dropping a coroutine that is running panics, and leaks its stack.
+///
+/// The call stacks are linked together the same way as those of generators, so a backtrace
+/// taken in a coroutine shows the chain of transfers that has led to it.
+///
+/// # Example
+///
+/// ```
+/// use std::rc::Rc;
+/// use fringe::{OsStack, Coroutine};
+///
+/// let double = Rc::new(Coroutine::new(OsStack::new(0).unwrap(), |current, mut value| {
+///   loop { value = current.suspend(value * 2) }
+/// }));
+/// let add_one = Coroutine::new(OsStack::new(0).unwrap(), move |current, mut value| {
+///   loop { value = current.transfer(&double, value + 1) }
+/// });
+/// println!("{}", add_one.resume(1)); // prints 4
+/// println!("{}", add_one.resume(2)); // prints 6
+/// ```
+#[derive(Debug)]
+pub struct Coroutine<T, Stack: stack::Stack> {
+  stack:    mem::ManuallyDrop<Stack>,
+  stack_id: debug::StackId,
+  slot:     *const Slot,
+  /// Whether the stack is known to be large enough to unwind when the coroutine is dropped.
+  #[cfg(feature = "std")]
+  unwind:   bool,
+  phantom:  PhantomData<*const T>
+}
+
+impl<T, Stack> Coroutine<T, Stack>
+    where Stack: stack::Stack {
+  /// Creates a new coroutine.
+  ///
+  /// If `stack` is smaller than [`MIN_STACK_SIZE`](../constant.MIN_STACK_SIZE.html),
+  /// its base is misaligned, or its guard is smaller than
+  /// [`MIN_GUARD_SIZE`](../constant.MIN_GUARD_SIZE.html), panics.
+  ///
+  /// See also the [contract](../trait.GuardedStack.html) that needs to be fulfilled by `stack`.
+  pub fn new<F>(stack: Stack, f: F) -> Coroutine<T, Stack>
+      where Stack: stack::GuardedStack,
+            F: FnOnce(&Current<T>, T) -> T + 'static {
+    match Coroutine::try_new(stack, f) {
+      Ok(coroutine) => coroutine,
+      Err(err) => panic!("cannot create coroutine: {}", err)
+    }
+  }
+
+  /// Same as `new`, but returns an error instead of panicking if `stack` is smaller than
+  /// [`MIN_STACK_SIZE`](../constant.MIN_STACK_SIZE.html), its base is misaligned,
+  /// or its guard is smaller than [`MIN_GUARD_SIZE`](../constant.MIN_GUARD_SIZE.html).
+  pub fn try_new<F>(stack: Stack, f: F) -> Result<Coroutine<T, Stack>, StackError>
+      where Stack: stack::GuardedStack,
+            F: FnOnce(&Current<T>, T) -> T + 'static {
+    StackError::check(&stack)?;
+    StackError::check_guard(&stack)?;
+    // The slot is stored at the base of the stack, and the environment of the coroutine
+    // function below it, so they have to leave enough room below them.
+    let size = stack.base() as usize - stack.limit() as usize;
+    let min = ::MIN_STACK_SIZE + env_footprint::<Slot>() + env_footprint::<F>();
+    if size < min { return Err(StackError::TooSmall { size, min }) }
+    #[allow(unused_mut)]
+    let mut coroutine = unsafe { Coroutine::unsafe_new(stack, f) };
+    #[cfg(feature = "std")] {
+      coroutine.unwind = true;
+    }
+    Ok(coroutine)
+  }
+
+  /// Same as `new`, but does not require `stack` to have a guard page.
+  ///
+  /// # Safety
+  ///
+  /// The coroutine function can easily violate memory safety by overflowing the stack,
+  /// so it must be known not to. Dropping the coroutine while it is suspended leaks
+  /// the values the coroutine function holds rather than unwinding its stack.
+  ///
+  /// See also the [contract](../trait.Stack.html) that needs to be fulfilled by `stack`.
+  pub unsafe fn unsafe_new<F>(stack: Stack, f: F) -> Coroutine<T, Stack>
+      where F: FnOnce(&Current<T>, T) -> T + 'static {
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    fn run<T, F>(f: F, current: &Current<T>, value: T) -> Kind<T>
+        where F: FnOnce(&Current<T>, T) -> T {
+      // Stop any unwinding here; the unwinder cannot cross into another stack.
+      // A panic is carried over to the resumer, and a cancellation simply finishes.
+      match panic::catch_unwind(AssertUnwindSafe(|| f(current, value))) {
+        Ok(value) => Kind::Value(value),
+        Err(payload) =>
+          if payload.is::<Cancelled>() { Kind::Cancel } else { Kind::Panicked(payload) }
+      }
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[inline(always)]
+    fn run<T, F>(f: F, current: &Current<T>, value: T) -> Kind<T>
+        where F: FnOnce(&Current<T>, T) -> T {
+      Kind::Value(f(current, value))
+    }
+
+    let stack_id = debug::StackId::register(&stack);
+    // The slot lives at the base of the stack, so that it does not move
+    // together with the coroutine.
+    let base = (stack.base() as usize - SLOT_SIZE) & !(::STACK_ALIGNMENT - 1);
+    let slot = base as *const Slot;
+    let context = Context::new(&Frame { stack: &stack, base }, move |caller: Context, packet: Packet<T>| {
+      let slot = &*slot;
+      let current = Current::<T>::receive(slot, caller, &packet);
+      let kind = match packet.kind {
+        Kind::Value(value) => run(f, &current, value),
+        #[cfg(feature = "std")]
+        Kind::Cancel => { drop(f); Kind::Cancel }
+        #[cfg(feature = "std")]
+        Kind::Panicked(_) => unreachable!("coroutine switched to with a panic")
+      };
+      // Past this point, the coroutine has dropped everything it has held, and only
+      // hands the result over to the resumer, which never switches back.
+      slot.state.set(match kind {
+        #[cfg(feature = "std")]
+        Kind::Panicked(_) => State::Panicked,
+        _ => State::Finished
+      });
+      let home = &*current.home.get();
+      (home.take(), Packet { kind, from: slot, home })
+    });
+    ptr::write(slot as *mut Slot, Slot {
+      context: Cell::new(Some(context)),
+      state:   Cell::new(State::Suspended),
+      base
+    });
+
+    Coroutine {
+      stack:   mem::ManuallyDrop::new(stack),
+      stack_id,
+      slot,
+      #[cfg(feature = "std")]
+      unwind:  false,
+      phantom: PhantomData
+    }
+  }
+
+  #[inline(always)]
+  fn slot(&self) -> &Slot {
+    unsafe { &*self.slot }
+  }
+
+  /// Switches to the coroutine, passing it `value`, and returns the value passed
+  /// to `Current::suspend()` by the coroutine that suspends next, or returned by
+  /// the coroutine function that returns next.
+  ///
+  /// If the coroutine is not suspended (i.e. `self.state() != State::Suspended`), panics.
+  #[inline]
+  pub fn resume(&self, value: T) -> T {
+    let home = Slot::new();
+    let packet = unsafe { home.switch(self.slot(), &home, Kind::Value(value)) };
+    match packet.kind {
+      Kind::Value(value) => value,
+      #[cfg(feature = "std")]
+      Kind::Panicked(payload) => panic::resume_unwind(payload),
+      #[cfg(feature = "std")]
+      Kind::Cancel => unreachable!("coroutine cancelled while being resumed")
+    }
+  }
+
+  /// Returns the state of the coroutine.
+  #[inline]
+  pub fn state(&self) -> State { self.slot().state.get() }
+
+  /// Extracts the stack from a coroutine when the coroutine function has returned.
+  /// If the coroutine function has not returned
+  /// (i.e. `self.state()` is `State::Suspended` or `State::Running`), panics.
+  pub fn unwrap(self) -> Stack {
+    match self.state() {
+      State::Suspended | State::Running => panic!("cannot unwrap a coroutine that has not finished"),
+      State::Finished | State::Panicked => unsafe {
+        let mut this = mem::ManuallyDrop::new(self);
+        ptr::drop_in_place(&mut this.stack_id);
+        ptr::read(&*this.stack)
+      }
+    }
+  }
+}
+
+impl<T, Stack> Drop for Coroutine<T, Stack>
+    where Stack: stack::Stack {
+  fn drop(&mut self) {
+    match self.state() {
+      State::Suspended => {
+        #[cfg(feature = "std")]
+        if self.unwind {
+          unsafe {
+            let home = Slot::new();
+            let mut packet: Packet<T> = home.switch(self.slot(), &home, Kind::Cancel);
+            // The coroutine function may catch the unwinding and suspend again, or return.
+            // Keep cancelling until it has actually finished.
+            while self.state() == State::Suspended {
+              packet = home.switch(self.slot(), &home, Kind::Cancel);
+            }
+            if let Kind::Panicked(payload) = packet.kind {
+              panic::resume_unwind(payload)
+            }
+          }
+        }
+      }
+      // The stack is in use; it is leaked, so that whatever runs on it can continue.
+      State::Running => panic!("coroutine dropped while running"),
+      State::Finished | State::Panicked => ()
+    }
+    unsafe { mem::ManuallyDrop::drop(&mut self.stack) }
+  }
+}
+
+/// Current is an interface provided to every coroutine function through which
+/// it transfers control to other coroutines.
+pub struct Current<T> {
+  slot:    *const Slot,
+  /// The slot of the context that has called `resume()`.
+  home:    Cell<*const Slot>,
+  phantom: PhantomData<*const T>
+}
+
+impl<T> fmt::Debug for Current<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Current").field("slot", &self.slot).finish()
+  }
+}
+
+impl<T> Current<T> {
+  /// Stores the context that has switched to the coroutine owning `slot`,
+  /// and takes over the resumer from `packet`.
+  #[inline(always)]
+  unsafe fn receive(slot: &Slot, context: Context, packet: &Packet<T>) -> Current<T> {
+    (*packet.from).context.set(Some(context));
+    Current { slot, home: Cell::new(packet.home), phantom: PhantomData }
+  }
+
+  #[inline(always)]
+  fn switch(&self, to: &Slot, value: T) -> T {
+    unsafe {
+      let packet = (*self.slot).switch(to, self.home.get(), Kind::Value(value));
+      self.home.set(packet.home);
+      match packet.kind {
+        Kind::Value(value) => value,
+        #[cfg(feature = "std")]
+        Kind::Cancel => cancelled(),
+        #[cfg(feature = "std")]
+        Kind::Panicked(_) => unreachable!("coroutine switched to with a panic")
+      }
+    }
+  }
+
+  /// Suspends the coroutine, transfers control to `to`, passing it `value`, and returns
+  /// the value passed by the coroutine, or the resumer, that transfers control back.
+  ///
+  /// If `to` is not suspended (i.e. `to.state() != State::Suspended`), panics.
+  ///
+  /// If the coroutine is dropped instead of being switched back to, this function
+  /// unwinds the coroutine stack rather than returning.
+  #[inline(always)]
+  pub fn transfer<Stack>(&self, to: &Coroutine<T, Stack>, value: T) -> T
+      where Stack: stack::Stack {
+    self.switch(to.slot(), value)
+  }
+
+  /// Suspends the coroutine and returns `value` from the `resume()` invocation that has
+  /// started the chain of transfers leading to it, and returns the value passed by
+  /// the coroutine, or the resumer, that transfers control back.
+  ///
+  /// If the coroutine is dropped instead of being switched back to, this function
+  /// unwinds the coroutine stack rather than returning.
+  #[inline(always)]
+  pub fn suspend(&self, value: T) -> T {
+    self.switch(unsafe { &*self.home.get() }, value)
+  }
+}
+
+/// The place where a suspended context is stored, along with its state.
+/// A coroutine has one at the base of its stack, and a resumer has one
+/// in the stack frame of `resume()`.
+struct Slot {
+  context: Cell<Option<Context>>,
+  state:   Cell<State>,
+  /// The base of the coroutine stack, which is where the slot is, or 0 for a resumer.
+  base:    usize
+}
+
+const SLOT_SIZE: usize = mem::size_of::<Slot>();
+
+impl Slot {
+  fn new() -> Slot {
+    Slot { context: Cell::new(None), state: Cell::new(State::Running), base: 0 }
+  }
+
+  /// Takes the context out of a suspended slot to switch to it. The call stack
+  /// of a coroutine is linked to the one of the context switching to it.
+  fn take(&self) -> Context {
+    match self.state.get() {
+      State::Suspended => {
+        self.state.set(State::Running);
+        let context = self.context.take().expect("suspended coroutine has no context");
+        if self.base != 0 { unsafe { context.linked(self.base) } } else { context }
+      }
+      State::Running => panic!("coroutine switched to while running"),
+      State::Finished | State::Panicked => panic!("coroutine switched to after it has finished")
+    }
+  }
+
+  /// Suspends the context owning `self` and switches to the one suspended in `to`,
+  /// passing it `kind`. Returns the packet passed by the context that switches back.
+  #[inline(always)]
+  unsafe fn switch<T>(&self, to: &Slot, home: *const Slot, kind: Kind<T>) -> Packet<T> {
+    let context = to.take();
+    self.state.set(State::Suspended);
+    let (context, packet): (Context, Packet<T>) =
+      context.switch_with(Packet { kind, from: self, home });
+    (*packet.from).context.set(Some(context));
+    packet
+  }
+}
+
+/// The value passed on every switch between coroutines.
+struct Packet<T> {
+  kind: Kind<T>,
+  /// The slot of the context that has switched, where the receiver stores it.
+  from: *const Slot,
+  /// The slot of the context that has called `resume()`.
+  home: *const Slot
+}
+
+enum Kind<T> {
+  /// A value passed by `resume()`, `transfer()` or `suspend()`, or returned by
+  /// the coroutine function.
+  Value(T),
+  /// A request to cancel the coroutine, or the acknowledgement that it has been cancelled.
+  #[cfg(feature = "std")]
+  Cancel,
+  /// The coroutine function has panicked; holds the payload of the panic.
+  #[cfg(feature = "std")]
+  Panicked(Box<dyn Any + Send>)
+}
+
+/// The payload of the unwinding that cancels a suspended coroutine.
+#[cfg(feature = "std")]
+struct Cancelled;
+
+#[cfg(feature = "std")]
+#[cold]
+fn cancelled() -> ! {
+  panic::resume_unwind(Box::new(Cancelled))
+}
@@ -0,0 +1,129 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use core::mem;
+use core::ptr;
+
+use arch;
+use stack;
+use stack_pointer::StackPointer;
+
+/// The size of the header written at the limit of a generator stack: a canary,
+/// followed by a word telling whether the generator is delegating to another one.
+pub const HEADER_SIZE: usize = 2 * mem::size_of::<usize>();
+
+/// The canary, before it is mixed with the address it is written at.
+const CANARY: u64 = 0x5afe_57ac_c0de_ca7a;
+
+/// The distance between the stack pointer passed to the context being switched to
+/// and the value written to its CFA slot.
+#[cfg(target_arch = "or1k")]
+const CFA_BIAS: usize = 8;
+#[cfg(not(target_arch = "or1k"))]
+const CFA_BIAS: usize = 0;
+
+/// Guard checks the header at the limit of a generator stack, and the stack pointers
+/// saved while the generator is suspended, to detect overflows and corruption of
+/// stacks without a guard page.
+#[derive(Debug, Clone, Copy)]
+pub struct Guard {
+  limit: usize,
+  frame: usize
+}
+
+impl Guard {
+  /// Writes the header at the limit of `stack`, whose initial frame has its base at `frame`.
+  pub unsafe fn arm(stack: &dyn stack::Stack, frame: usize) -> Guard {
+    let guard = Guard { limit: stack.limit() as usize, frame };
+    ptr::write_unaligned(guard.canary_ptr(), guard.canary());
+    guard.set_delegating(false);
+    guard
+  }
+
+  #[inline(always)]
+  fn canary(&self) -> usize {
+    CANARY as usize ^ self.limit
+  }
+
+  #[inline(always)]
+  fn canary_ptr(&self) -> *mut usize {
+    self.limit as *mut usize
+  }
+
+  #[inline(always)]
+  fn delegating_ptr(&self) -> *mut usize {
+    (self.limit + mem::size_of::<usize>()) as *mut usize
+  }
+
+  /// Records whether the generator function is delegating to another one, in which case
+  /// the stack pointer it suspends with lies on the stack of the other one.
+  #[inline(always)]
+  pub unsafe fn set_delegating(&self, delegating: bool) {
+    ptr::write_unaligned(self.delegating_ptr(), delegating as usize)
+  }
+
+  /// Checks the header, and that `stack_ptr`, saved by the generator function
+  /// when it `action`, lies on its stack.
+  pub unsafe fn check(&self, stack_ptr: StackPointer, action: &str) {
+    let delegating = self.check_header();
+    let stack_ptr = stack_ptr.0 as usize;
+    if !delegating && !self.contains(stack_ptr) {
+      panic!("stack pointer {:#x} saved when the generator {} lies outside of its stack [{:#x}, {:#x})",
+             stack_ptr, action, self.limit + HEADER_SIZE, self.frame)
+    }
+  }
+
+  /// Checks the header, and that the generator function is running on its stack,
+  /// before it switches away from it.
+  #[inline(always)]
+  pub unsafe fn check_running(&self) {
+    self.check_header();
+    let marker = 0u8;
+    let stack_ptr = &marker as *const u8 as usize;
+    if !self.contains(stack_ptr) {
+      panic!("generator is running at {:#x}, outside of its stack [{:#x}, {:#x})",
+             stack_ptr, self.limit + HEADER_SIZE, self.frame)
+    }
+  }
+
+  /// Checks that the CFA slot at the base of the initial frame still holds the stack pointer
+  /// of the context that has last switched to the stack, `switcher`. If it does not,
+  /// the slot is repaired before panicking, so that the panic can be reported with
+  /// a backtrace, which is found through the slot.
+  pub unsafe fn check_cfa(&self, switcher: StackPointer) {
+    let slot = (self.frame as *mut usize).offset(arch::CFA_SLOT);
+    let expected = switcher.0 as usize - CFA_BIAS;
+    let value = *slot;
+    if value != expected {
+      *slot = expected;
+      panic!("CFA slot at {:#x} holds {:#x} instead of {:#x}: \
+              the base of the generator stack was overwritten",
+             slot as usize, value, expected)
+    }
+  }
+
+  /// Checks the header, and returns whether the generator function is delegating.
+  unsafe fn check_header(&self) -> bool {
+    let canary = ptr::read_unaligned(self.canary_ptr());
+    if canary != self.canary() {
+      panic!("stack canary at {:#x} overwritten with {:#x}: \
+              the generator has overflowed its stack [{:#x}, {:#x})",
+             self.limit, canary, self.limit, self.frame)
+    }
+    match ptr::read_unaligned(self.delegating_ptr()) {
+      0 => false,
+      1 => true,
+      value => panic!("stack header at {:#x} overwritten with {:#x}: \
+                       the generator has overflowed its stack [{:#x}, {:#x})",
+                      self.delegating_ptr() as usize, value, self.limit, self.frame)
+    }
+  }
+
+  #[inline(always)]
+  fn contains(&self, stack_ptr: usize) -> bool {
+    stack_ptr >= self.limit + HEADER_SIZE && stack_ptr < self.frame
+  }
+}
@@ -5,6 +5,7 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 pub use self::imp::*;
+pub use self::guard::*;
 
 #[cfg(feature = "valgrind")]
 #[path = "valgrind.rs"]
@@ -22,3 +23,29 @@ mod imp {
     }
   }
 }
+
+#[cfg(feature = "hardening")]
+#[path = "hardening.rs"]
+mod guard;
+
+#[cfg(not(feature = "hardening"))]
+mod guard {
+  use stack;
+  use stack_pointer::StackPointer;
+  pub const HEADER_SIZE: usize = 0;
+  #[derive(Debug, Clone, Copy)]
+  pub struct Guard;
+  /// No-op since no hardening
+  impl Guard {
+    #[inline(always)]
+    pub unsafe fn arm(_stack: &dyn stack::Stack, _frame: usize) -> Guard { Guard }
+    #[inline(always)]
+    pub unsafe fn set_delegating(&self, _delegating: bool) {}
+    #[inline(always)]
+    pub unsafe fn check(&self, _stack_ptr: StackPointer, _action: &str) {}
+    #[inline(always)]
+    pub unsafe fn check_running(&self) {}
+    #[inline(always)]
+    pub unsafe fn check_cfa(&self, _switcher: StackPointer) {}
+  }
+}
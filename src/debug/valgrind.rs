@@ -4,24 +4,95 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-extern crate valgrind_request;
+
+// Valgrind client requests are a magic sequence of instructions that does
+// nothing when running natively, and is intercepted by Valgrind otherwise.
+// See valgrind.h for the canonical definitions.
+#[allow(unused_imports)]
+use core::arch::asm;
 
 use stack;
-use self::valgrind_request::{stack_register, stack_deregister};
+
+type Value = usize;
+
+const STACK_REGISTER:   Value = 0x1501;
+const STACK_DEREGISTER: Value = 0x1502;
+
+#[cfg(target_arch = "x86_64")]
+#[inline(always)]
+unsafe fn do_client_request(default: Value, args: &[Value; 6]) -> Value {
+  let result;
+  asm!(
+    r#"
+      rolq $3,  %rdi ; rolq $13, %rdi
+      rolq $61, %rdi ; rolq $51, %rdi
+      xchgq %rbx, %rbx
+    "#,
+    inout("rdx") default => result,
+    in("rax") args.as_ptr(),
+    options(att_syntax, nostack));
+  result
+}
+
+#[cfg(target_arch = "x86")]
+#[inline(always)]
+unsafe fn do_client_request(default: Value, args: &[Value; 6]) -> Value {
+  let result;
+  asm!(
+    r#"
+      roll $3,  %edi ; roll $13, %edi
+      roll $29, %edi ; roll $19, %edi
+      xchgl %ebx, %ebx
+    "#,
+    inout("edx") default => result,
+    in("eax") args.as_ptr(),
+    options(att_syntax, nostack));
+  result
+}
+
+#[cfg(target_arch = "aarch64")]
+#[inline(always)]
+unsafe fn do_client_request(default: Value, args: &[Value; 6]) -> Value {
+  let result;
+  asm!(
+    r#"
+      ror x12, x12, #3  ;  ror x12, x12, #13
+      ror x12, x12, #51 ;  ror x12, x12, #61
+      orr x10, x10, x10
+    "#,
+    inout("x3") default => result,
+    in("x4") args.as_ptr(),
+    options(nostack));
+  result
+}
+
+// Valgrind does not support any other architecture we do.
+#[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))]
+#[inline(always)]
+unsafe fn do_client_request(default: Value, _args: &[Value; 6]) -> Value {
+  default
+}
 
 #[derive(Debug)]
-pub struct StackId(self::valgrind_request::Value);
+pub struct StackId(Value);
 
 impl StackId {
   #[inline(always)]
   pub fn register<Stack: stack::Stack>(stack: &Stack) -> StackId {
-    StackId(stack_register(stack.limit(), stack.base()))
+    StackId(unsafe {
+      do_client_request(0, &[STACK_REGISTER,
+                             stack.limit() as Value,
+                             stack.base()  as Value,
+                             0, 0, 0])
+    })
   }
 }
 
 impl Drop for StackId {
   #[inline(always)]
   fn drop(&mut self) {
-    stack_deregister(self.0)
+    unsafe {
+      do_client_request(0, &[STACK_DEREGISTER, self.0, 0, 0, 0, 0]);
+    }
   }
 }
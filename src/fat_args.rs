@@ -5,38 +5,49 @@
 
 //! Adaptor methods for types that are bigger than a CPU Word
 use core::marker::PhantomData;
-use core::mem;
+use core::mem::{self, MaybeUninit};
 use core::ptr;
 
 use stack::Stack;
-use stack_pointer::StackPointer;
+use stack_pointer::{StackPointer, NUM_REGS};
 
-const NUM_REGS: usize = 1;
+/// Returns whether a `T` is passed by value in `N` registers, rather than
+/// through a pointer in the first one.
+#[inline(always)]
+pub const fn by_value<T, const N: usize>() -> bool {
+  mem::size_of::<T>() <= mem::size_of::<usize>() * N
+    && mem::align_of::<T>() <= mem::align_of::<usize>()
+}
 
+/// Moves the value `data_ptr` points to into `N` registers, or if it does not fit in them,
+/// passes `data_ptr` in the first one. Either way the value is moved out, and the
+/// original must be forgotten rather than dropped; in the latter case it must also stay
+/// where it is until `from_regs` has been called.
+///
+/// Any padding in the value leaves the corresponding bytes of the registers uninitialized,
+/// which is why they are `MaybeUninit`.
 #[inline(always)]
-pub unsafe fn to_regs<T>(data_ptr: *const T) -> [usize; NUM_REGS] {
-  let mut regs: [usize; NUM_REGS] = [mem::uninitialized()];
-  if mem::size_of::<T>() <= (mem::size_of::<usize>() * NUM_REGS) {
+pub unsafe fn to_regs<T, const N: usize>(data_ptr: *const T) -> [MaybeUninit<usize>; N] {
+  let mut regs = [MaybeUninit::uninit(); N];
+  if by_value::<T, N>() {
     // in regs
-    ptr::write(&mut regs as *mut _ as *mut _,
-               ptr::read(data_ptr));
+    ptr::copy_nonoverlapping(data_ptr, regs.as_mut_ptr() as *mut T, 1);
   } else {
     // via pointer
-    regs[0] = data_ptr as usize;
+    regs[0] = MaybeUninit::new(data_ptr as usize);
   }
   regs
 }
 
+/// Retrieves the value passed by `to_regs`.
 #[inline(always)]
-pub unsafe fn from_regs<T>(regs: [usize; NUM_REGS]) -> T {
-  let ptr = if mem::size_of::<T>() <= mem::size_of::<usize>() * NUM_REGS
-    && mem::align_of::<T>() <= mem::align_of::<usize>()
-  {
+pub unsafe fn from_regs<T, const N: usize>(regs: [MaybeUninit<usize>; N]) -> T {
+  let ptr = if by_value::<T, N>() {
     // in regs
-    &regs as *const _ as *const _
+    regs.as_ptr() as *const T
   } else {
     // via pointer
-    regs[0] as *const _
+    regs[0].assume_init() as *const T
   };
   ptr::read(ptr)
 }
@@ -45,17 +56,26 @@ pub unsafe fn from_regs<T>(regs: [usize; NUM_REGS]) -> T {
 
 /// Initializes a stack with the trampoline for a closure.
 ///
+/// The closure must not let a panic escape, since there is no frame below it
+/// to unwind into. Catch it instead and hand the payload over to another context
+/// through `swap`, which can then continue unwinding with `resume_unwind`.
+///
 /// The phantom arguments are used so that `init0` and `init1` can be
 /// called with a single closure literal of unnamable type.
 #[inline]
-pub unsafe fn init0<F>(stack: &Stack) -> (StackPointer, PhantomData<F>)
-  where F: FnOnce(StackPointer) -> !
+pub unsafe fn init0<F>(stack: &dyn Stack) -> (StackPointer, PhantomData<F>)
+  where F: FnOnce(StackPointer)
 {
   unsafe extern "C" fn closure_wrapper<F>(a0: usize, sp: StackPointer) -> !
-    where F: FnOnce(StackPointer) -> !
+    where F: FnOnce(StackPointer)
   {
-    let closure: F = from_regs::<F>([a0]);
-    closure(sp)
+    // The environment is always passed through a pointer, since it may contain padding,
+    // and the trampoline receives the first register as an initialized word.
+    let closure: F = ptr::read(a0 as *const F);
+    // A panic that escapes the closure aborts the process here.
+    closure(sp);
+    // There is no caller to return to.
+    panic!("closure returned from its context")
   }
 
   let sp = StackPointer::init(stack, closure_wrapper::<F>);
@@ -65,37 +85,39 @@ pub unsafe fn init0<F>(stack: &Stack) -> (StackPointer, PhantomData<F>)
 /// Initialize the stack with the closure environment *and switch*.
 ///
 /// It is the responsibility of the closure to immediately yield `R`
-/// if control wishes to be returned to caller immediately. Use a
-/// reference if closure is a DST.
+/// if control wishes to be returned to caller immediately, and to never
+/// return. Use a reference if closure is a DST.
 ///
 /// The phantom arguments are used so that `init0` and `init1` can be
 /// called with a single closure literal of unnamable type.
 #[inline]
 pub unsafe fn init1<F, R>((new_sp, _): (StackPointer, PhantomData<F>),
-                          new_stack: Option<&Stack>,
+                          new_stack: Option<&dyn Stack>,
                           closure: F)
                           -> (StackPointer, R)
-  where F: FnOnce(StackPointer) -> !
+  where F: FnOnce(StackPointer)
 {
-  let (sp2, ret) = swap(closure, new_sp, new_stack);
-  (sp2, ret)
+  // The initial call only receives the first register.
+  let mut args = [MaybeUninit::uninit(); NUM_REGS];
+  args[0] = MaybeUninit::new(&closure as *const F as usize);
+  let (rets, old_sp) = StackPointer::swap_args(args, new_sp, new_stack);
+  mem::forget(closure);
+  (old_sp, from_regs(rets))
 }
 
 /// `I` and `O` can be any size
 #[inline]
-pub unsafe fn swap<I, O>(args: I, new_sp: StackPointer, new_stack: Option<&Stack>)
+pub unsafe fn swap<I, O>(args: I, new_sp: StackPointer, new_stack: Option<&dyn Stack>)
                          -> (StackPointer, O)
 {
-  let [arg0] = to_regs(&args);
-  let (param0, old_sp) = StackPointer::swap(arg0, new_sp, new_stack);
+  let (rets, old_sp) = StackPointer::swap_args(to_regs(&args), new_sp, new_stack);
   mem::forget(args);
-  (old_sp, from_regs([param0]))
+  (old_sp, from_regs(rets))
 }
 
 #[cfg(test)]
 mod test {
   extern crate rand;
-  extern crate simd;
 
   use core::fmt::Debug;
 
@@ -106,7 +128,7 @@ mod test {
   fn simple_round_trip_test<T: PartialEq + Debug + rand::Rand>() {
     let data = rand::thread_rng().gen::<T>();
     unsafe {
-      assert_eq!(data, from_regs(to_regs(&data)));
+      assert_eq!(data, from_regs(to_regs::<T, NUM_REGS>(&data)));
     }
     ::std::mem::forget(data);
   }
@@ -131,9 +153,14 @@ mod test {
     simple_round_trip_test::<u32>();
   }
 
+  #[test]
+  fn round_trip_padded() {
+    simple_round_trip_test::<(u8, u32)>();
+  }
+
   #[test]
   fn round_trip_max_by_value() {
-    simple_round_trip_test::<[usize; 2]>();
+    simple_round_trip_test::<[usize; NUM_REGS]>();
   }
 
   #[test]
@@ -150,7 +177,8 @@ mod test {
     unsafe {
       let rets = init0(&stack);
       init1::<_, ()>(rets, None, move |initializer_sp| {
-        swap::<(), !>((), initializer_sp, None).1
+        swap::<(), ()>((), initializer_sp, None);
+        unreachable!()
       })
     };
   }
@@ -12,20 +12,102 @@
 //! afterwards.
 
 use core::marker::PhantomData;
-use core::{ptr, mem};
+use core::{cmp, fmt, ptr, mem};
+use core::mem::MaybeUninit;
 use core::cell::Cell;
 
-use stack;
+#[cfg(feature = "std")]
+use std::any::Any;
+#[cfg(feature = "std")]
+use std::boxed::Box;
+#[cfg(feature = "std")]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "std")]
+use std::thread::{self, ThreadId};
+
+use stack::{self, StackError};
+use painted_stack::PaintedStack;
 use debug;
-use stack_pointer::StackPointer;
+use stack_pointer::{Regs, StackPointer, NUM_REGS};
+use fat_args;
+use context;
+#[cfg(all(unix, feature = "std"))]
+use overflow;
+#[cfg(unix)]
+use os::{Stack as OsStack, IoError};
+#[cfg(feature = "std")]
+use implicit;
+use core::any::TypeId;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
   /// Generator can be resumed. This is the initial state.
   Runnable,
   /// Generator cannot be resumed. This is the state of the generator after
-  /// the generator function has returned or panicked.
-  Unavailable
+  /// the generator function has returned or has been cancelled.
+  Unavailable,
+  /// Generator cannot be resumed. This is the state of the generator after
+  /// the generator function has panicked.
+  Panicked,
+  /// Generator cannot be resumed. This is the state of the generator after
+  /// the generator function has overflowed its stack and the overflow has been
+  /// recovered from; see [`overflow::set_recoverable`](../overflow/fn.set_recoverable.html).
+  Poisoned
+}
+
+/// The error returned when the generator function has overflowed its stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackOverflow;
+
+impl fmt::Display for StackOverflow {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "generator has overflowed its stack")
+  }
+}
+
+#[cfg(feature = "std")]
+impl ::std::error::Error for StackOverflow {}
+
+/// The result of resuming a generator with `resume_state()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeneratorState<Yield, Return> {
+  /// The generator function has suspended itself, yielding a value.
+  Yielded(Yield),
+  /// The generator function has returned a value.
+  Complete(Return)
+}
+
+/// A reference lent by a generator function through `Yielder::suspend_ref()`.
+///
+/// A generator whose output type is `Lend<T>` is a lending generator: the generator function
+/// yields references to `T`, which may point into its own stack, and `resume_ref()` returns
+/// them as borrows of the generator that remain valid until it is resumed again. A `Lend<T>`
+/// obtained through `resume()` is opaque, since nothing keeps its referent alive.
+///
+/// # Example
+///
+/// ```
+/// use fringe::{OsStack, Generator};
+/// use fringe::generator::Lend;
+///
+/// let stack = OsStack::new(0).unwrap();
+/// let mut words: Generator<(), Lend<[u8]>, _> = Generator::new(stack, move |yielder, ()| {
+///   let mut buffer = [0u8; 16];
+///   for word in &["foo", "quux"] {
+///     buffer[..word.len()].copy_from_slice(word.as_bytes());
+///     yielder.suspend_ref(&buffer[..word.len()]);
+///   }
+/// });
+/// println!("{:?}", words.resume_ref(())); // prints Some([102, 111, 111])
+/// println!("{:?}", words.resume_ref(())); // prints Some([113, 117, 117, 120])
+/// println!("{:?}", words.resume_ref(())); // prints None
+/// ```
+pub struct Lend<T: ?Sized>(*const T);
+
+impl<T: ?Sized> fmt::Debug for Lend<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "Lend({:p})", self.0)
+  }
 }
 
 /// Generator wraps a function and allows suspending its execution more than once, returning
@@ -38,14 +120,49 @@ pub enum State {
 /// the `resume()` call will return `None`, and it will return `None` every time it is called
 /// after that.
 ///
+/// The generator function may also return a value of type `Return`. To retrieve it, resume
+/// the generator using `resume_state()` instead, which returns `GeneratorState::Yielded(output)`
+/// for every value the generator function suspends with and `GeneratorState::Complete(value)`
+/// once it returns `value`. `resume()` discards the returned value.
+///
 /// If the generator function panics, the panic is propagated through the `resume()` call as usual.
+/// With the `std` feature, the panic is caught on the generator stack and carried over to
+/// the resumer before it continues unwinding there; `resume_catching()` returns the panic
+/// payload as `Err(payload)` instead, without unwinding the resumer.
 ///
 /// After the generator function returns or panics, it is safe to reclaim the generator stack
 /// using `unwrap()`.
 ///
+/// Instead of an input, an error can be thrown into a suspended generator using `throw()`;
+/// the generator function observes it as `Err(err)` returned from `Yielder::try_suspend()`.
+///
+/// If the generator is dropped while the generator function is suspended, or `cancel()`
+/// is called, the generator function is resumed one last time and the pending `suspend()`
+/// call unwinds its stack, running the destructors of every value it holds. (This requires
+/// the `std` feature; without it, such values are leaked.) If the generator function catches
+/// that unwinding and suspends again, the new `suspend()` call unwinds as well. A destructor
+/// that suspends while the stack is being unwound therefore panics during the unwinding,
+/// which aborts the process, unless it catches that panic with `catch_unwind`.
+/// Unwinding takes a fair amount of stack space, so dropping a generator created with
+/// `unsafe_new`, whose stack may be too small for it and has no guard page to catch
+/// the overflow, leaks these values instead.
+///
 /// `state()` can be used to determine whether the generator function has returned;
-/// the state is `State::Runnable` after creation and suspension, and `State::Unavailable`
-/// once the generator function returns or panics.
+/// the state is `State::Runnable` after creation and suspension, `State::Unavailable`
+/// once the generator function returns, and `State::Panicked` once it panics.
+///
+/// If recovery from stack overflows is enabled using
+/// [`overflow::set_recoverable`](../overflow/fn.set_recoverable.html), a generator function
+/// that overflows its stack is abandoned: the resumer receives `Err(StackOverflow)` from
+/// `try_resume()` and the state becomes `State::Poisoned`. Its stack is not unwound, so
+/// every value it holds is leaked, including any locks.
+///
+/// A generator never leaves the thread it was created on, so neither the generator function
+/// nor the values passed in and out of it have to be `Send`; it is fine to yield an `Rc` or
+/// to hold a `RefCell` borrow across a suspension point.
+///
+/// The generator function must be `'static`, since a suspended generator can outlive any
+/// stack frame; to borrow from the stack frame of the caller, use [`scope`](../fn.scope.html).
 ///
 /// When the input type is `()`, a generator implements the Iterator trait.
 ///
@@ -66,6 +183,26 @@ pub enum State {
 /// println!("{:?}", add_one.resume(0)); // prints None
 /// ```
 ///
+/// # Return value example
+///
+/// ```
+/// use fringe::{OsStack, Generator};
+/// use fringe::generator::GeneratorState;
+///
+/// let stack = OsStack::new(0).unwrap();
+/// let mut sum = Generator::new(stack, move |yielder, mut input| {
+///   let mut total = 0;
+///   while input != 0 {
+///     total += input;
+///     input = yielder.suspend(total)
+///   }
+///   format!("total: {}", total)
+/// });
+/// println!("{:?}", sum.resume_state(1)); // prints Yielded(1)
+/// println!("{:?}", sum.resume_state(2)); // prints Yielded(3)
+/// println!("{:?}", sum.resume_state(0)); // prints Complete("total: 3")
+/// ```
+///
 /// # Iterator example
 ///
 /// ```
@@ -80,69 +217,349 @@ pub enum State {
 /// println!("{:?}", nat.next()); // prints Some(2)
 /// ```
 #[derive(Debug)]
-pub struct Generator<Input: Send, Output: Send, Stack: stack::Stack> {
+pub struct Generator<Input, Output, Stack: stack::Stack, Return = ()> {
   state:     State,
+  #[cfg(all(unix, feature = "std"))]
+  overflow:  Option<overflow::Registration>,
   stack:     Stack,
   stack_id:  debug::StackId,
   stack_ptr: StackPointer,
-  phantom:   PhantomData<(*const Input, *const Output)>
+  frame:     usize,
+  guard:     debug::Guard,
+  started:   bool,
+  /// The cell in which the generator function records whether it is delegating,
+  /// or null if it has not started.
+  delegates: *const Cell<bool>,
+  /// Whether the stack is known to be large enough to unwind when the generator is dropped.
+  #[cfg(feature = "std")]
+  unwind:    bool,
+  phantom:   PhantomData<(*const Input, *const Output, *const Return)>
 }
 
-impl<Input, Output, Stack> Generator<Input, Output, Stack>
-    where Input: Send, Output: Send, Stack: stack::Stack {
+impl<Input, Output, Stack, Return> Generator<Input, Output, Stack, Return>
+    where Stack: stack::Stack {
   /// Creates a new generator.
   ///
+  /// If `stack` is smaller than [`MIN_STACK_SIZE`](../constant.MIN_STACK_SIZE.html),
+  /// its base is misaligned, or its guard is smaller than
+  /// [`MIN_GUARD_SIZE`](../constant.MIN_GUARD_SIZE.html), panics.
+  ///
   /// See also the [contract](../trait.GuardedStack.html) that needs to be fulfilled by `stack`.
-  pub fn new<F>(stack: Stack, f: F) -> Generator<Input, Output, Stack>
+  pub fn new<F>(stack: Stack, f: F) -> Generator<Input, Output, Stack, Return>
+      where Stack: stack::GuardedStack,
+            F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return + 'static {
+    match Generator::try_new(stack, f) {
+      Ok(generator) => generator,
+      Err(err) => panic!("cannot create generator: {}", err)
+    }
+  }
+
+  /// Same as `new`, but returns an error instead of panicking if `stack` is smaller than
+  /// [`MIN_STACK_SIZE`](../constant.MIN_STACK_SIZE.html), its base is misaligned,
+  /// or its guard is smaller than [`MIN_GUARD_SIZE`](../constant.MIN_GUARD_SIZE.html).
+  pub fn try_new<F>(stack: Stack, f: F) -> Result<Generator<Input, Output, Stack, Return>, StackError>
       where Stack: stack::GuardedStack,
-            F: FnOnce(&mut Yielder<Input, Output>, Input) + Send {
-    unsafe { Generator::unsafe_new(stack, f) }
+            F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return + 'static {
+    unsafe { Generator::try_new_unbounded(stack, f) }
+  }
+
+  /// Same as `try_new`, but does not require the generator function to be `'static`.
+  /// The caller must ensure that the generator is dropped, or never resumed again,
+  /// before anything the generator function borrows goes away.
+  pub(crate) unsafe fn try_new_unbounded<F>(stack: Stack, f: F)
+                                            -> Result<Generator<Input, Output, Stack, Return>, StackError>
+      where Stack: stack::GuardedStack,
+            F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return {
+    check_stack::<Stack, F>(&stack)?;
+    #[allow(unused_mut)]
+    let mut generator = Generator::new_unbounded(stack, f);
+    #[cfg(feature = "std")] {
+      generator.unwind = true;
+    }
+    #[cfg(all(unix, feature = "std"))] {
+      let guard_size = generator.stack.guard_size();
+      generator.overflow = overflow::Registration::register(&generator.frame(), guard_size,
+                                                            core::any::type_name::<F>());
+    }
+    Ok(generator)
   }
 
   /// Same as `new`, but does not require `stack` to have a guard page.
   ///
-  /// This function is unsafe because the generator function can easily violate
-  /// memory safety by overflowing the stack. It is useful in environments where
-  /// guarded stacks do not exist, e.g. in absence of an MMU.
+  /// It is useful in environments where guarded stacks do not exist, e.g. in absence of an MMU.
+  /// The size of `stack` is not checked either; see `try_new`.
+  ///
+  /// # Safety
+  ///
+  /// The generator function can easily violate memory safety by overflowing the stack,
+  /// so it must be known not to. A stack smaller than
+  /// [`MIN_STACK_SIZE`](../constant.MIN_STACK_SIZE.html) is only suitable for a generator
+  /// function that never panics and is never cancelled with `cancel()`; dropping the generator
+  /// while it is suspended leaks the values the generator function holds rather than
+  /// unwinding its stack.
   ///
   /// See also the [contract](../trait.Stack.html) that needs to be fulfilled by `stack`.
-  pub unsafe fn unsafe_new<F>(stack: Stack, f: F) -> Generator<Input, Output, Stack>
-      where F: FnOnce(&mut Yielder<Input, Output>, Input) + Send {
-    unsafe extern "C" fn generator_wrapper<Input, Output, Stack, F>(env: usize, stack_ptr: StackPointer) -> !
-        where Input: Send, Output: Send, Stack: stack::Stack,
-              F: FnOnce(&mut Yielder<Input, Output>, Input) {
-      // Retrieve our environment from the callee and return control to it.
-      let f = ptr::read(env as *const F);
-      let (data, stack_ptr) = StackPointer::swap(0, stack_ptr, None);
-      // See the second half of Yielder::suspend_bare.
-      let input = ptr::read(data as *const Input);
-      // Run the body of the generator.
-      let mut yielder = Yielder::new(stack_ptr);
-      f(&mut yielder, input);
-      // Past this point, the generator has dropped everything it has held.
-      loop { yielder.suspend_bare(None); }
-    }
+  pub unsafe fn unsafe_new<F>(stack: Stack, f: F) -> Generator<Input, Output, Stack, Return>
+      where F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return + 'static {
+    Generator::new_unbounded(stack, f)
+  }
 
+  /// Same as `unsafe_new`, but does not require the generator function to be `'static`;
+  /// see `try_new_unbounded`.
+  unsafe fn new_unbounded<F>(stack: Stack, f: F) -> Generator<Input, Output, Stack, Return>
+      where F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return {
     let stack_id  = debug::StackId::register(&stack);
-    let stack_ptr = StackPointer::init(&stack, generator_wrapper::<Input, Output, Stack, F>);
-
-    // Transfer environment to the callee.
-    let stack_ptr = StackPointer::swap(&f as *const F as usize, stack_ptr, Some(&stack)).1;
-    mem::forget(f);
+    let (frame, guard, stack_ptr) = Generator::<Input, Output, Stack, Return>::start(&stack, f);
 
     Generator {
       state:     State::Runnable,
-      stack:     stack,
-      stack_id:  stack_id,
-      stack_ptr: stack_ptr,
+      #[cfg(all(unix, feature = "std"))]
+      overflow:  None,
+      stack,
+      stack_id,
+      stack_ptr,
+      frame,
+      guard,
+      started:   false,
+      delegates: ptr::null(),
+      #[cfg(feature = "std")]
+      unwind:    false,
       phantom:   PhantomData
     }
   }
 
+  /// Moves the generator function `f` to the base of `stack` and lays out the initial
+  /// frame below it, without switching to `stack`. Returns the base of the initial frame,
+  /// which is where `f` is stored, the guard of the stack, and the stack pointer to switch to
+  /// in order to start `f`.
+  unsafe fn start<F>(stack: &Stack, f: F) -> (usize, debug::Guard, StackPointer)
+      where F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return {
+    unsafe extern "C" fn generator_wrapper<Input, Output, Return, F>(start: usize, stack_ptr: StackPointer) -> !
+        where F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return {
+      // Retrieve our environment, which the resumer tells us the location of.
+      let Start { args, env, guard, delegates } = ptr::read(start as *const Start);
+      let f = ptr::read(env as *const F);
+      // Tell the generator where we record whether we are delegating.
+      let delegation = Cell::new(false);
+      *delegates = &delegation;
+      let mut yielder = Yielder::new(stack_ptr, guard, &delegation);
+      let args = yielder.accept(stack_ptr, args);
+      // If the generator was cancelled before it was ever resumed, there is
+      // no stack to unwind; just drop the environment.
+      let result = if first(&args) == CANCEL {
+        drop(f);
+        Err(Event::Cancelled)
+      } else {
+        // See the second half of Yielder::suspend_bare.
+        let input = unpack::<Input>(args);
+        // Run the body of the generator.
+        run(f, &mut yielder, input)
+      };
+      // Past this point, the generator has dropped everything it has held
+      // except for the return value, which the resumer moves out of our stack.
+      match result {
+        Ok(value) => {
+          let value = mem::ManuallyDrop::new(value);
+          yielder.finish(Event::Returned(&*value as *const Return as usize))
+        }
+        Err(event) => yielder.finish(event)
+      }
+    }
+
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    fn run<Input, Output, Return, F>(f: F, yielder: &mut Yielder<Input, Output>, input: Input)
+                                    -> Result<Return, Event<Output>>
+        where F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return {
+      // Stop any unwinding here; the unwinder cannot cross into the resumer stack.
+      // A panic is carried over to the resumer, and a cancellation simply finishes.
+      panic::catch_unwind(AssertUnwindSafe(|| f(yielder, input))).map_err(|payload| {
+        if payload.is::<Cancelled>() { Event::Cancelled } else { Event::Panicked(payload) }
+      })
+    }
+
+    #[cfg(not(feature = "std"))]
+    #[inline(always)]
+    fn run<Input, Output, Return, F>(f: F, yielder: &mut Yielder<Input, Output>, input: Input)
+                                    -> Result<Return, Event<Output>>
+        where F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return {
+      Ok(f(yielder, input))
+    }
+
+    let size = stack.base() as usize - stack.limit() as usize;
+    assert!(env_size::<F>() + mem::align_of::<F>() <= size,
+            "generator function does not fit on its stack");
+    let frame = (stack.base() as usize - env_size::<F>()) & !(env_align::<F>() - 1);
+    ptr::write(frame as *mut F, f);
+    let guard = debug::Guard::arm(stack, frame);
+    let stack_ptr = StackPointer::init(&Frame { stack, base: frame },
+                                       generator_wrapper::<Input, Output, Return, F>);
+    (frame, guard, stack_ptr)
+  }
+
+  /// Returns the part of the stack below the environment of the generator function.
+  #[inline(always)]
+  fn frame(&self) -> Frame<'_> {
+    Frame { stack: &self.stack, base: self.frame }
+  }
+
+  /// Switches to the generator function, passing it `args`, and returns the arguments
+  /// it has passed back. The first switch also passes it the location of its environment,
+  /// and since it only receives the first argument, passes all of them through memory.
+  #[inline(always)]
+  unsafe fn switch(&mut self, args: Regs) -> Regs {
+    let start = Start { args, env: self.frame, guard: self.guard, delegates: &mut self.delegates };
+    let args = if self.started { args } else { by_pointer(&start) };
+    self.guard.check(self.stack_ptr, "was suspended");
+    self.started = true;
+    #[cfg(feature = "std")]
+    let current = implicit::leave();
+    // Contexts switched from the generator function do not know where its stack is.
+    let base = context::enter(0);
+    let (args_out, stack_ptr) = StackPointer::swap_args(args, self.stack_ptr, Some(&self.frame()));
+    context::enter(base);
+    #[cfg(feature = "std")]
+    implicit::restore(current);
+    // The overflow handler has abandoned the generator stack, which no longer holds
+    // anything to check.
+    #[cfg(feature = "std")]
+    let overflowed = first(&args_out) == OVERFLOWED;
+    #[cfg(not(feature = "std"))]
+    let overflowed = false;
+    if !overflowed { self.guard.check(stack_ptr, "suspended") }
+    self.stack_ptr = stack_ptr;
+    args_out
+  }
+
   /// Resumes the generator and return the next value it yields.
-  /// If the generator function has returned, returns `None`.
+  /// If the generator function has returned, returns `None`, discarding
+  /// the value it has returned.
   #[inline]
   pub fn resume(&mut self, input: Input) -> Option<Output> {
+    match self.state {
+      State::Runnable => match self.resume_state(input) {
+        GeneratorState::Yielded(item) => Some(item),
+        GeneratorState::Complete(_)   => None
+      },
+      State::Unavailable | State::Panicked | State::Poisoned => None
+    }
+  }
+
+  /// Same as `resume`, but if the generator function overflows its stack and
+  /// the overflow is recovered from, or has done so before, returns `Err(StackOverflow)`.
+  #[cfg(feature = "std")]
+  #[inline]
+  pub fn try_resume(&mut self, input: Input) -> Result<Option<Output>, StackOverflow> {
+    match self.state {
+      State::Runnable => match self.resume_event(input) {
+        Event::Yielded(item)     => Ok(Some(item)),
+        Event::Returned(value)   => {
+          drop(unsafe { ptr::read(value as *const Return) });
+          Ok(None)
+        }
+        Event::Panicked(payload) => panic::resume_unwind(payload),
+        Event::Overflowed        => Err(StackOverflow),
+        Event::Cancelled         => unreachable!("generator cancelled while being resumed")
+      },
+      State::Unavailable | State::Panicked => Ok(None),
+      State::Poisoned => Err(StackOverflow)
+    }
+  }
+
+  /// Resumes the generator. Returns `GeneratorState::Yielded(item)` if the generator
+  /// function suspends with `item`, or `GeneratorState::Complete(value)` if it returns
+  /// `value`.
+  ///
+  /// If the generator function has already returned or panicked
+  /// (i.e. `self.state() != State::Runnable`), panics.
+  #[inline]
+  pub fn resume_state(&mut self, input: Input) -> GeneratorState<Output, Return> {
+    Generator::<Input, Output, Stack, Return>::event_state(self.resume_event(input))
+  }
+
+  /// Resumes the generator by making the `Yielder::try_suspend()` call the generator function
+  /// is suspended in return `Err(err)` instead of the next input. Returns the next value
+  /// the generator function yields. If the generator function has returned, returns `None`,
+  /// discarding the value it has returned.
+  ///
+  /// If the generator function is suspended in `Yielder::suspend()` instead, or in
+  /// `Yielder::try_suspend()` expecting an error of another type, that call panics.
+  ///
+  /// If the generator function has not started yet, has already returned or panicked
+  /// (i.e. `self.state() != State::Runnable`), panics.
+  #[inline]
+  pub fn throw<E: 'static>(&mut self, err: E) -> Option<Output> {
+    match self.throw_state(err) {
+      GeneratorState::Yielded(item) => Some(item),
+      GeneratorState::Complete(_)   => None
+    }
+  }
+
+  /// Same as `throw`, but returns `GeneratorState::Yielded(item)` if the generator
+  /// function suspends with `item`, or `GeneratorState::Complete(value)` if it returns
+  /// `value`.
+  #[inline]
+  pub fn throw_state<E: 'static>(&mut self, err: E) -> GeneratorState<Output, Return> {
+    if self.state == State::Runnable && !self.started {
+      panic!("cannot throw into a generator that has not started")
+    }
+    let thrown = Thrown::new(&err);
+    let event = unsafe { self.send_event(words(THROW, &thrown as *const Thrown as usize)) };
+    mem::forget(err);
+    Generator::<Input, Output, Stack, Return>::event_state(event)
+  }
+
+  /// Converts the event the generator function has suspended with into the state
+  /// returned by `resume_state`.
+  #[inline(always)]
+  fn event_state(event: Event<Output>) -> GeneratorState<Output, Return> {
+    match event {
+      Event::Yielded(item)   => GeneratorState::Yielded(item),
+      Event::Returned(value) => GeneratorState::Complete(unsafe { ptr::read(value as *const Return) }),
+      #[cfg(feature = "std")]
+      Event::Panicked(payload) => panic::resume_unwind(payload),
+      #[cfg(feature = "std")]
+      Event::Overflowed      => panic::panic_any(StackOverflow),
+      Event::Cancelled       => unreachable!("generator cancelled while being resumed")
+    }
+  }
+
+  /// Same as `resume_state`, but if the generator function panics, returns `Err(payload)`
+  /// with the payload of the panic instead of unwinding the resumer. Afterwards, the state
+  /// is `State::Panicked`. If the generator function overflows its stack and the overflow
+  /// is recovered from, the payload is `StackOverflow`.
+  ///
+  /// If the generator function has already returned or panicked
+  /// (i.e. `self.state() != State::Runnable`), panics.
+  #[cfg(feature = "std")]
+  #[inline]
+  pub fn resume_catching(&mut self, input: Input)
+                        -> Result<GeneratorState<Output, Return>, Box<dyn Any + Send>> {
+    match self.resume_event(input) {
+      Event::Yielded(item)     => Ok(GeneratorState::Yielded(item)),
+      Event::Returned(value)   => Ok(GeneratorState::Complete(unsafe { ptr::read(value as *const Return) })),
+      Event::Panicked(payload) => Err(payload),
+      Event::Overflowed        => Err(Box::new(StackOverflow)),
+      Event::Cancelled         => unreachable!("generator cancelled while being resumed")
+    }
+  }
+
+  /// Switches to the generator function and retrieves the event it has suspended with.
+  /// If the event is `Event::Returned`, the return value has to be moved out of
+  /// the generator stack before it is switched to again.
+  #[inline(always)]
+  fn resume_event(&mut self, input: Input) -> Event<Output> {
+    unsafe {
+      let event = self.send_event(pack(INPUT, &input));
+      mem::forget(input);
+      event
+    }
+  }
+
+  /// Switches to the generator function, passing it `args`, and retrieves the event
+  /// it has suspended with; see `resume_event`.
+  #[inline(always)]
+  unsafe fn send_event(&mut self, args: Regs) -> Event<Output> {
     match self.state {
       State::Runnable => {
         // Set the state to Unavailable. Since we have exclusive access to the generator,
@@ -151,20 +568,57 @@ impl<Input, Output, Stack> Generator<Input, Output, Stack>
         self.state = State::Unavailable;
 
         // Switch to the generator function, and retrieve the yielded value.
-        let val = unsafe {
-          let (data_out, stack_ptr) = StackPointer::swap(&input as *const Input as usize, self.stack_ptr, Some(&self.stack));
-          self.stack_ptr = stack_ptr;
-          mem::forget(input);
-          ptr::read(data_out as *const Option<Output>)
-        };
-
-        // Unless the generator function has returned, it can be switched to again, so
-        // set the state to Runnable.
-        if val.is_some() { self.state = State::Runnable }
-
-        val
+        let event = read_event(self.switch(args));
+
+        match event {
+          // Unless the generator function has returned, it can be switched to again, so
+          // set the state to Runnable.
+          Event::Yielded(_)  => self.state = State::Runnable,
+          #[cfg(feature = "std")]
+          Event::Panicked(_) => self.state = State::Panicked,
+          #[cfg(feature = "std")]
+          Event::Overflowed  => self.state = State::Poisoned,
+          _ => ()
+        }
+
+        event
+      }
+      State::Unavailable | State::Panicked | State::Poisoned =>
+        panic!("generator resumed after it has finished")
+    }
+  }
+
+  /// Cancels the generator. If the generator function is suspended, it is resumed
+  /// one last time and its stack is unwound, running the destructors of every value
+  /// it holds; if it has not started yet, it is dropped without being called.
+  /// Afterwards, the state is `State::Unavailable` and the stack can be reclaimed
+  /// using `unwrap()`.
+  ///
+  /// This is also done when a suspended generator is dropped, unless it was created
+  /// with `unsafe_new`.
+  #[cfg(feature = "std")]
+  pub fn cancel(&mut self) {
+    if let State::Runnable = self.state {
+      self.state = State::Unavailable;
+
+      unsafe {
+        loop {
+          let data_out = self.switch(words(CANCEL, 0));
+          // The generator function may catch the unwinding and suspend again, or return.
+          // Discard the value and keep cancelling until it has actually finished.
+          match read_event::<Output>(data_out) {
+            Event::Yielded(_)        => continue,
+            Event::Returned(value)   => drop(ptr::read(value as *const Return)),
+            Event::Panicked(payload) => {
+              self.state = State::Panicked;
+              panic::resume_unwind(payload)
+            }
+            Event::Overflowed        => self.state = State::Poisoned,
+            Event::Cancelled         => ()
+          }
+          break
+        }
       }
-      State::Unavailable => None
     }
   }
 
@@ -172,54 +626,762 @@ impl<Input, Output, Stack> Generator<Input, Output, Stack>
   #[inline]
   pub fn state(&self) -> State { self.state }
 
+  /// Starts over with a new generator function `f` on the same stack once the generator
+  /// function has returned, without releasing the stack and registering it again.
+  /// If the generator function has not returned
+  /// (i.e. `self.state() == State::Runnable`), panics. If the stack is too small for `f`,
+  /// panics as `new` would.
+  pub fn reset<F>(&mut self, f: F)
+      where Stack: stack::GuardedStack,
+            F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return + 'static {
+    if self.state == State::Runnable {
+      panic!("cannot reset a generator that has not finished")
+    }
+    if let Err(err) = check_stack::<Stack, F>(&self.stack) {
+      panic!("cannot reset generator: {}", err)
+    }
+    let (frame, guard, stack_ptr) = unsafe { Generator::<Input, Output, Stack, Return>::start(&self.stack, f) };
+    self.frame = frame;
+    self.guard = guard;
+    self.stack_ptr = stack_ptr;
+    self.started = false;
+    self.delegates = ptr::null();
+    self.state = State::Runnable;
+    #[cfg(feature = "std")] {
+      self.unwind = true;
+    }
+    #[cfg(all(unix, feature = "std"))]
+    if let Some(ref overflow) = self.overflow {
+      overflow.update(&self.frame(), core::any::type_name::<F>())
+    }
+  }
+
+  /// Same as `reset`, but the new generator function may have different input,
+  /// output and return types.
+  /// If the generator function has not returned
+  /// (i.e. `self.state() == State::Runnable`), panics. If the stack is too small for `f`,
+  /// panics as `new` would.
+  pub fn recycle<NewInput, NewOutput, NewReturn, F>(self, f: F)
+                                                   -> Generator<NewInput, NewOutput, Stack, NewReturn>
+      where Stack: stack::GuardedStack,
+            F: FnOnce(&mut Yielder<NewInput, NewOutput>, NewInput) -> NewReturn + 'static {
+    if self.state == State::Runnable {
+      panic!("cannot recycle a generator that has not finished")
+    }
+    if let Err(err) = check_stack::<Stack, F>(&self.stack) {
+      panic!("cannot recycle generator: {}", err)
+    }
+    unsafe {
+      // Take the stack and its registrations apart from the generator, so that they are
+      // released as usual if `start` panics.
+      let this = mem::ManuallyDrop::new(self);
+      let stack = ptr::read(&this.stack);
+      let stack_id = ptr::read(&this.stack_id);
+      #[cfg(all(unix, feature = "std"))]
+      let overflow = ptr::read(&this.overflow);
+      let (frame, guard, stack_ptr) = Generator::<NewInput, NewOutput, Stack, NewReturn>::start(&stack, f);
+      let generator = Generator {
+        state:     State::Runnable,
+        #[cfg(all(unix, feature = "std"))]
+        overflow,
+        stack,
+        stack_id,
+        stack_ptr,
+        frame,
+        guard,
+        started:   false,
+        delegates: ptr::null(),
+        #[cfg(feature = "std")]
+        unwind:    true,
+        phantom:   PhantomData
+      };
+      #[cfg(all(unix, feature = "std"))]
+      if let Some(ref overflow) = generator.overflow {
+        overflow.update(&generator.frame(), core::any::type_name::<F>())
+      }
+      generator
+    }
+  }
+
   /// Extracts the stack from a generator when the generator function has returned.
   /// If the generator function has not returned
   /// (i.e. `self.state() == State::Runnable`), panics.
   pub fn unwrap(self) -> Stack {
     match self.state {
-      State::Runnable    => panic!("Argh! Bastard! Don't touch that!"),
-      State::Unavailable => self.stack
+      State::Runnable => panic!("Argh! Bastard! Don't touch that!"),
+      State::Unavailable | State::Panicked | State::Poisoned => unsafe {
+        let mut this = mem::ManuallyDrop::new(self);
+        #[cfg(all(unix, feature = "std"))]
+        ptr::drop_in_place(&mut this.overflow);
+        ptr::drop_in_place(&mut this.stack_id);
+        ptr::read(&this.stack)
+      }
     }
   }
 }
 
+impl<Input, Output, Stack, Return> Generator<Input, Output, PaintedStack<Stack>, Return>
+    where Stack: stack::Stack {
+  /// Returns the number of bytes of the stack that the generator function has used
+  /// at most; see [`PaintedStack::high_water`](../struct.PaintedStack.html#method.high_water).
+  pub fn stack_high_water(&self) -> usize {
+    self.stack.generator_high_water()
+  }
+}
+
+#[cfg(unix)]
+impl<Input, Output, Return> Generator<Input, Output, OsStack, Return> {
+  /// Returns the pages of the stack that the generator function is not using to the operating
+  /// system, and returns their size. This is meant for a generator on a
+  /// [lazy](../struct.OsStackBuilder.html#method.lazy) stack that has gone deep before, but is
+  /// suspended at a shallow point; the pages are faulted in again if it goes deep again.
+  ///
+  /// While the generator function is delegating to another one, or is suspended from
+  /// the stack of another generator, such as a scoped generator calling `suspend` on its
+  /// yielder, where on its stack it is suspended is not known, so nothing is returned.
+  pub fn decommit_unused_stack(&mut self) -> Result<usize, IoError> {
+    let start = stack::Stack::limit(&self.stack) as usize + debug::HEADER_SIZE;
+    let end = match self.state {
+      State::Runnable => {
+        // While the generator function is delegating, the stack pointer it has saved
+        // lies on the stack of the generator it delegates to.
+        if !self.delegates.is_null() && unsafe { (*self.delegates).get() } { return Ok(0) }
+        // The generator function uses nothing below the stack pointer it has saved,
+        // unless it lies on another stack.
+        let stack_ptr = self.stack_ptr.0 as usize;
+        if stack_ptr < start || stack_ptr >= self.frame { return Ok(0) }
+        stack_ptr
+      }
+      // Nothing below the initial frame is used once the generator function has finished.
+      State::Unavailable | State::Panicked | State::Poisoned => self.frame
+    };
+    unsafe { self.stack.decommit_range(start, end) }
+  }
+}
+
+impl<Input, T: ?Sized, Stack, Return> Generator<Input, Lend<T>, Stack, Return>
+    where Stack: stack::Stack {
+  /// Same as `resume`, but returns the reference lent by the generator function
+  /// through `Yielder::suspend_ref()`. The reference is valid until the generator
+  /// is resumed again.
+  #[inline]
+  pub fn resume_ref(&mut self, input: Input) -> Option<&T> {
+    // The generator function is suspended within the suspend_ref() call that lent
+    // the referent, and it stays there for as long as the generator is borrowed.
+    self.resume(input).map(|item| unsafe { &*item.0 })
+  }
+
+  /// Same as `resume_state`, but returns the reference lent by the generator function
+  /// through `Yielder::suspend_ref()`. The reference is valid until the generator
+  /// is resumed again.
+  #[inline]
+  pub fn resume_state_ref(&mut self, input: Input) -> GeneratorState<&T, Return> {
+    match self.resume_state(input) {
+      GeneratorState::Yielded(item)   => GeneratorState::Yielded(unsafe { &*item.0 }),
+      GeneratorState::Complete(value) => GeneratorState::Complete(value)
+    }
+  }
+}
+
+impl<Input, Output, Stack, Return> Drop for Generator<Input, Output, Stack, Return>
+    where Stack: stack::Stack {
+  fn drop(&mut self) {
+    #[cfg(feature = "std")]
+    if self.unwind { self.cancel() }
+  }
+}
+
+/// The value passed to the generator function instead of a pointer to the input
+/// to request cancellation. A pointer to the input can never be null.
+const CANCEL: usize = 0;
+
+/// The value passed to the generator function instead of a pointer to the input
+/// when the input is passed in registers. A pointer to the input is never 1.
+const INPUT: usize = 1;
+
+/// The value passed to the generator function instead of a pointer to the input when
+/// another generator function delegates to it, along with a pointer to a `Handoff`.
+/// A pointer to the input is never 2.
+const DELEGATE: usize = 2;
+
+/// The value passed to the generator function instead of a pointer to the input when
+/// an error is thrown into it, along with a pointer to a `Thrown`.
+/// A pointer to the input is never 3.
+const THROW: usize = 3;
+
+/// The value passed to the resumer instead of a pointer to an event when the generator
+/// function yields a value that is passed in registers. A pointer to an event is always
+/// aligned to a word, so it can never be 2.
+const YIELDED: usize = 2;
+
+/// Number of registers values can be passed in, besides the first one.
+const PAYLOAD_REGS: usize = NUM_REGS - 1;
+
+/// Prepares `value` for being passed to another context: in the registers following
+/// the first one if it fits in them, with `tag` in the first one, and otherwise
+/// through a pointer in the first one. The value is copied; the original must be forgotten.
+#[inline(always)]
+unsafe fn pack<T>(tag: usize, value: &T) -> Regs {
+  if fat_args::by_value::<T, PAYLOAD_REGS>() {
+    let mut args = [MaybeUninit::new(tag); NUM_REGS];
+    args[1..].copy_from_slice(&fat_args::to_regs::<T, PAYLOAD_REGS>(value));
+    args
+  } else {
+    by_pointer(value)
+  }
+}
+
+/// Prepares `value` for being passed to another context through a pointer in the first register.
+#[inline(always)]
+fn by_pointer<T>(value: &T) -> Regs {
+  words(value as *const T as usize, 0)
+}
+
+/// Prepares `first` and `second` for being passed to another context in the first two registers.
+#[inline(always)]
+fn words(first: usize, second: usize) -> Regs {
+  let mut args = [MaybeUninit::uninit(); NUM_REGS];
+  args[0] = MaybeUninit::new(first);
+  args[1] = MaybeUninit::new(second);
+  args
+}
+
+/// Retrieves the first register, which always holds a tag or a pointer.
+#[inline(always)]
+fn first(args: &Regs) -> usize {
+  unsafe { args[0].assume_init() }
+}
+
+/// Retrieves the second register, which holds a pointer if the first one holds `THROW`
+/// or `DELEGATE`.
+#[inline(always)]
+unsafe fn second(args: &Regs) -> usize {
+  args[1].assume_init()
+}
+
+/// Retrieves a value prepared by `pack`.
+#[inline(always)]
+unsafe fn unpack<T>(args: Regs) -> T {
+  if fat_args::by_value::<T, PAYLOAD_REGS>() {
+    let mut regs = [MaybeUninit::uninit(); PAYLOAD_REGS];
+    regs.copy_from_slice(&args[1..]);
+    fat_args::from_regs::<T, PAYLOAD_REGS>(regs)
+  } else {
+    ptr::read(first(&args) as *const T)
+  }
+}
+
+/// The value passed to the generator function the first time it is switched to.
+struct Start {
+  /// The arguments of the first switch: the input, or `CANCEL`.
+  args:      Regs,
+  /// The address of the generator function.
+  env:       usize,
+  /// The guard of the generator stack.
+  guard:     debug::Guard,
+  /// Where to store the address of the cell in which the generator function records
+  /// whether it is delegating.
+  delegates: *mut *const Cell<bool>
+}
+
+/// The value passed to a generator function that another one delegates to.
+struct Handoff {
+  /// The arguments the generator function would be resumed with: the input.
+  args:    Regs,
+  /// The cell holding the stack pointer of the resumer of the delegating generator.
+  resumer: *const Cell<StackPointer>
+}
+
+/// An error thrown into the generator function, which lives on the resumer stack.
+struct Thrown {
+  type_id: TypeId,
+  err:     *const (),
+  drop:    unsafe fn(*const ())
+}
+
+impl Thrown {
+  fn new<E: 'static>(err: &E) -> Thrown {
+    unsafe fn drop_err<E>(err: *const ()) {
+      ptr::drop_in_place(err as *mut E)
+    }
+
+    Thrown { type_id: TypeId::of::<E>(), err: err as *const E as *const (), drop: drop_err::<E> }
+  }
+
+  /// Moves the error out of the resumer stack if it is of type `E`, and drops it otherwise.
+  unsafe fn take<E: 'static>(&self) -> Option<E> {
+    if self.type_id == TypeId::of::<E>() {
+      Some(ptr::read(self.err as *const E))
+    } else {
+      self.discard();
+      None
+    }
+  }
+
+  /// Drops the error.
+  unsafe fn discard(&self) {
+    (self.drop)(self.err)
+  }
+}
+
+/// Checks that `stack` is suitable for a generator function `F`; see `Generator::try_new`.
+fn check_stack<Stack, F>(stack: &Stack) -> Result<(), StackError>
+    where Stack: stack::Stack + stack::GuardedStack {
+  StackError::check(stack)?;
+  StackError::check_guard(stack)?;
+  // The environment of the generator function is stored at the base of the stack,
+  // so it has to leave enough room below it.
+  let size = stack.base() as usize - stack.limit() as usize;
+  let min = ::MIN_STACK_SIZE + env_footprint::<F>();
+  if size < min { return Err(StackError::TooSmall { size, min }) }
+  Ok(())
+}
+
+/// Returns the alignment of the environment of a generator function `F` on the stack.
+fn env_align<F>() -> usize {
+  cmp::max(mem::align_of::<F>(), ::STACK_ALIGNMENT)
+}
+
+/// Returns the amount of stack space taken by the environment of a generator function `F`,
+/// provided the stack base is aligned to `STACK_ALIGNMENT`.
+fn env_size<F>() -> usize {
+  (mem::size_of::<F>() + env_align::<F>() - 1) & !(env_align::<F>() - 1)
+}
+
+/// Returns the amount of stack space taken by the environment of a function `F` at most,
+/// including the padding that aligns it, provided the stack base is aligned to `STACK_ALIGNMENT`.
+pub(crate) fn env_footprint<F>() -> usize {
+  env_size::<F>() + env_align::<F>() - ::STACK_ALIGNMENT
+}
+
+/// The part of a generator stack below the environment of the generator function,
+/// where the initial frame is laid out. It is what the generator function runs on.
+pub(crate) struct Frame<'a> {
+  pub(crate) stack: &'a dyn stack::Stack,
+  pub(crate) base:  usize
+}
+
+impl<'a> stack::Stack for Frame<'a> {
+  fn base(&self) -> *mut u8 { self.base as *mut u8 }
+  fn limit(&self) -> *mut u8 { self.stack.limit() }
+}
+
+/// The value passed to the resumer instead of a pointer to an event when the generator
+/// function has overflowed its stack and has been abandoned by the overflow handler.
+/// A pointer to an event is always aligned to a word, so it can never be 1.
+#[cfg(feature = "std")]
+pub(crate) const OVERFLOWED: usize = 1;
+
+/// Retrieves the event the generator function has passed to the resumer.
+#[inline(always)]
+unsafe fn read_event<Output>(args: Regs) -> Event<Output> {
+  #[cfg(feature = "std")]
+  { if first(&args) == OVERFLOWED { return Event::Overflowed } }
+  if fat_args::by_value::<Output, PAYLOAD_REGS>() && first(&args) == YIELDED {
+    return Event::Yielded(unpack(args))
+  }
+  ptr::read(first(&args) as *const Event<Output>)
+}
+
+/// The value passed from the generator function to the resumer on every context switch.
+enum Event<Output> {
+  /// The generator function has suspended with a value.
+  Yielded(Output),
+  /// The generator function has returned; holds the address of the return value,
+  /// which lives on the generator stack and must be moved out of it.
+  Returned(usize),
+  /// The generator function has been cancelled, or was never started.
+  Cancelled,
+  /// The generator function has panicked; holds the payload of the panic.
+  #[cfg(feature = "std")]
+  Panicked(Box<dyn Any + Send>),
+  /// The generator function has overflowed its stack and has been abandoned.
+  #[cfg(feature = "std")]
+  Overflowed
+}
+
+/// The payload of the unwinding that cancels a suspended generator.
+#[cfg(feature = "std")]
+struct Cancelled;
+
+#[cold]
+fn cancelled() -> ! {
+  #[cfg(feature = "std")]
+  panic::resume_unwind(Box::new(Cancelled));
+  #[cfg(not(feature = "std"))]
+  unreachable!("generators cannot be cancelled without the std feature")
+}
+
 /// Yielder is an interface provided to every generator through which it
 /// returns a value.
 #[derive(Debug)]
-pub struct Yielder<Input: Send, Output: Send> {
+pub struct Yielder<Input, Output> {
   stack_ptr: Cell<StackPointer>,
-  phantom: PhantomData<(*const Input, *const Output)>
+  /// The cell holding the stack pointer of the resumer: `stack_ptr` of the outermost
+  /// generator in a chain of delegation, or null if the generator is not delegated to.
+  resumer:   Cell<*const Cell<StackPointer>>,
+  /// The stack pointer of the generator that has delegated to this one, if any.
+  delegator: Cell<Option<StackPointer>>,
+  /// The cell recording whether the generator function is delegating to another one,
+  /// which lives on the generator stack.
+  delegates: *const Cell<bool>,
+  guard:     debug::Guard,
+  /// The type of the yielder, if it has made itself current.
+  #[cfg(feature = "std")]
+  implicit:  Cell<Option<TypeId>>,
+  phantom:   PhantomData<(*const Input, *const Output)>
 }
 
-impl<Input, Output> Yielder<Input, Output>
-    where Input: Send, Output: Send {
-  fn new(stack_ptr: StackPointer) -> Yielder<Input, Output> {
+impl<Input, Output> Yielder<Input, Output> {
+  fn new(stack_ptr: StackPointer, guard: debug::Guard, delegates: &Cell<bool>) -> Yielder<Input, Output> {
     Yielder {
       stack_ptr: Cell::new(stack_ptr),
-      phantom: PhantomData
+      resumer:   Cell::new(ptr::null()),
+      delegator: Cell::new(None),
+      delegates,
+      guard,
+      #[cfg(feature = "std")]
+      implicit:  Cell::new(None),
+      phantom:   PhantomData
     }
   }
 
+  /// Returns the cell holding the stack pointer of the resumer.
+  #[inline(always)]
+  fn resumer(&self) -> &Cell<StackPointer> {
+    let resumer = self.resumer.get();
+    if resumer.is_null() { &self.stack_ptr } else { unsafe { &*resumer } }
+  }
+
+  /// Checks the generator stack before switching away from it. The CFA slot of a generator
+  /// that is delegated to is written by the delegator, and that of any other one by its resumer.
   #[inline(always)]
-  fn suspend_bare(&self, val: Option<Output>) -> Input {
+  unsafe fn check(&self) {
+    self.guard.check_running();
+    self.guard.check_cfa(self.delegator.get().unwrap_or_else(|| self.resumer().get()))
+  }
+
+  /// Records the context that has switched to the generator function with `args`.
+  /// If it is a generator delegating to this one, the resumer is taken over from it.
+  /// Returns the arguments carrying the input.
+  #[inline(always)]
+  unsafe fn accept(&self, stack_ptr: StackPointer, args: Regs) -> Regs {
+    if first(&args) == DELEGATE {
+      let handoff = ptr::read(second(&args) as *const Handoff);
+      self.delegator.set(Some(stack_ptr));
+      self.resumer.set(handoff.resumer);
+      handoff.args
+    } else {
+      self.resumer().set(stack_ptr);
+      args
+    }
+  }
+
+  /// Passes `val` to the resumer, and returns the arguments carrying the input, or
+  /// the thrown error, once the generator is resumed. Unwinds if it is cancelled instead.
+  #[inline(always)]
+  fn suspend_bare(&self, val: Event<Output>) -> Regs {
     unsafe {
-      let (data, stack_ptr) = StackPointer::swap(&val as *const Option<Output> as usize, self.stack_ptr.get(), None);
-      self.stack_ptr.set(stack_ptr);
+      let args = match val {
+        Event::Yielded(ref item) if fat_args::by_value::<Output, PAYLOAD_REGS>() => pack(YIELDED, item),
+        _ => by_pointer(&val)
+      };
+      self.check();
+      let (data, stack_ptr) = StackPointer::swap_args(args, self.resumer().get(), None);
       mem::forget(val);
-      ptr::read(data as *const Input)
+      #[cfg(feature = "std")]
+      if let Some(type_id) = self.implicit.get() {
+        implicit::enter(self as *const Self as *const (), type_id)
+      }
+      let data = self.accept(stack_ptr, data);
+      if first(&data) == CANCEL { cancelled() }
+      data
     }
   }
 
+  /// Passes the final event of the generator function to the generator delegating
+  /// to it, if any, or otherwise to the resumer.
+  #[inline(always)]
+  unsafe fn finish(&self, val: Event<Output>) -> ! {
+    let target = self.delegator.get().unwrap_or_else(|| self.resumer().get());
+    StackPointer::swap_args(by_pointer(&val), target, None);
+    unreachable!("generator resumed after it has finished")
+  }
+
   /// Suspends the generator and returns `Some(item)` from the `resume()`
   /// invocation that resumed the generator.
+  ///
+  /// If the generator is cancelled instead of being resumed, this function
+  /// unwinds the generator stack rather than returning.
   #[inline(always)]
   pub fn suspend(&self, item: Output) -> Input {
-    self.suspend_bare(Some(item))
+    let data = self.suspend_bare(Event::Yielded(item));
+    if first(&data) == THROW {
+      unsafe { (*(second(&data) as *const Thrown)).discard() }
+      panic!("error thrown into a generator suspended in suspend()")
+    }
+    unsafe { unpack(data) }
+  }
+
+  /// Same as `suspend`, but if the generator is resumed with `Generator::throw(err)`
+  /// instead of an input, returns `Err(err)`.
+  ///
+  /// If the error thrown is not of type `E`, panics.
+  #[inline(always)]
+  pub fn try_suspend<E: 'static>(&self, item: Output) -> Result<Input, E> {
+    let data = self.suspend_bare(Event::Yielded(item));
+    unsafe {
+      if first(&data) == THROW {
+        match (*(second(&data) as *const Thrown)).take::<E>() {
+          Some(err) => Err(err),
+          None => panic!("error of an unexpected type thrown into a generator")
+        }
+      } else {
+        Ok(unpack(data))
+      }
+    }
+  }
+
+  /// Makes the yielder current whenever the generator function runs, so that code it calls
+  /// can suspend it through [`fringe::suspend`](../fn.suspend.html) without being passed
+  /// the yielder.
+  #[cfg(feature = "std")]
+  pub fn make_current(&self)
+      where Input: 'static, Output: 'static {
+    let type_id = TypeId::of::<Yielder<Input, Output>>();
+    self.implicit.set(Some(type_id));
+    implicit::enter(self as *const Self as *const (), type_id)
+  }
+
+  /// Resumes `inner` with `input` and forwards everything it yields to the resumer,
+  /// and every input from the resumer to it, until the inner generator function returns;
+  /// returns its return value. If it panics, the panic is propagated; if it overflows
+  /// its stack and the overflow is recovered from, this function panics with `StackOverflow`.
+  ///
+  /// The resumer switches directly to the inner generator function and back,
+  /// so forwarding an item takes no more context switches than yielding it.
+  ///
+  /// If `inner` has already returned or panicked (i.e. `inner.state() != State::Runnable`),
+  /// panics. If the generator is cancelled while delegating, `inner` is cancelled as well,
+  /// and this function unwinds the generator stack rather than returning.
+  pub fn delegate<Stack, Return>(&self, inner: &mut Generator<Input, Output, Stack, Return>,
+                                 input: Input) -> Return
+      where Stack: stack::Stack {
+    if inner.state != State::Runnable {
+      panic!("generator resumed after it has finished")
+    }
+    inner.state = State::Unavailable;
+    unsafe {
+      let handoff = Handoff { args: pack(INPUT, &input), resumer: self.resumer() };
+      let args = words(DELEGATE, &handoff as *const Handoff as usize);
+      // The inner generator function only switches back once it has finished.
+      (*self.delegates).set(true);
+      self.guard.set_delegating(true);
+      let data_out = inner.switch(args);
+      self.guard.set_delegating(false);
+      (*self.delegates).set(false);
+      mem::forget(input);
+      match read_event::<Output>(data_out) {
+        Event::Returned(value) => ptr::read(value as *const Return),
+        Event::Cancelled => cancelled(),
+        #[cfg(feature = "std")]
+        Event::Panicked(payload) => {
+          inner.state = State::Panicked;
+          panic::resume_unwind(payload)
+        }
+        #[cfg(feature = "std")]
+        Event::Overflowed => {
+          inner.state = State::Poisoned;
+          panic::panic_any(StackOverflow)
+        }
+        Event::Yielded(_) => unreachable!("delegated generator yielded to its delegator")
+      }
+    }
+  }
+}
+
+impl<Input, T: ?Sized> Yielder<Input, Lend<T>> {
+  /// Suspends the generator and lends `item` to the `resume_ref()` invocation that
+  /// resumed the generator. `item` may borrow from the generator stack, since
+  /// the generator function does not proceed until it is resumed again.
+  ///
+  /// If the generator is cancelled instead of being resumed, this function
+  /// unwinds the generator stack rather than returning.
+  #[inline(always)]
+  pub fn suspend_ref(&self, item: &T) -> Input {
+    self.suspend(Lend(item))
+  }
+}
+
+impl<Output, Stack, Return> Iterator for Generator<(), Output, Stack, Return>
+    where Stack: stack::Stack {
+  type Item = Output;
+
+  fn next(&mut self) -> Option<Self::Item> { self.resume(()) }
+}
+
+/// SendGenerator is a generator that can be sent to another thread while it is suspended,
+/// and resumed there.
+///
+/// The compiler cannot check what the generator function holds across its suspension points,
+/// so creating a SendGenerator is unsafe. In addition to the generator function, its environment
+/// and every value passed in and out of it being `Send`, the generator function must not hold
+/// any value that is tied to the thread it runs on across a call to `suspend()`. This includes
+/// `Rc`s, `MutexGuard`s and references to thread-local storage; for example, a reference obtained
+/// within `LocalKey::with` would point to the thread-local storage of the previous thread, which
+/// may have exited by then. Accessing thread-local storage is fine as long as no reference to it
+/// is held across a suspension point.
+///
+/// To help find violations of the latter, `set_migration_check(true)` makes resuming
+/// the generator on a different thread than the one it was last resumed on panic.
+///
+/// # Example
+///
+/// ```
+/// use std::thread;
+/// use fringe::{OsStack, SendGenerator};
+///
+/// let stack = OsStack::new(0).unwrap();
+/// let mut nat = unsafe {
+///   SendGenerator::new(stack, move |yielder, ()| {
+///     for i in 1.. { yielder.suspend(i) }
+///   })
+/// };
+/// println!("{:?}", nat.resume(())); // prints Some(1)
+/// let mut nat = thread::spawn(move || {
+///   println!("{:?}", nat.resume(())); // prints Some(2)
+///   nat
+/// }).join().unwrap();
+/// println!("{:?}", nat.resume(())); // prints Some(3)
+/// ```
+#[derive(Debug)]
+pub struct SendGenerator<Input, Output, Stack: stack::Stack, Return = ()> {
+  generator: Generator<Input, Output, Stack, Return>,
+  #[cfg(feature = "std")]
+  thread:    Option<ThreadId>,
+  #[cfg(feature = "std")]
+  check:     bool
+}
+
+unsafe impl<Input, Output, Stack, Return> Send for SendGenerator<Input, Output, Stack, Return>
+    where Input: Send, Output: Send, Stack: stack::Stack + Send, Return: Send {}
+
+impl<Input, Output, Stack, Return> SendGenerator<Input, Output, Stack, Return>
+    where Input: Send, Output: Send, Stack: stack::Stack + Send, Return: Send {
+  /// Creates a new generator that can be sent to another thread.
+  ///
+  /// If `stack` is smaller than [`MIN_STACK_SIZE`](../constant.MIN_STACK_SIZE.html),
+  /// its base is misaligned, or its guard is smaller than
+  /// [`MIN_GUARD_SIZE`](../constant.MIN_GUARD_SIZE.html), panics.
+  ///
+  /// # Safety
+  ///
+  /// The generator function must not hold any value that is tied to the thread it runs on
+  /// across a suspension point; see the [type documentation](struct.SendGenerator.html).
+  pub unsafe fn new<F>(stack: Stack, f: F) -> SendGenerator<Input, Output, Stack, Return>
+      where Stack: stack::GuardedStack,
+            F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return + Send + 'static {
+    SendGenerator {
+      generator: Generator::new(stack, f),
+      #[cfg(feature = "std")]
+      thread:    None,
+      #[cfg(feature = "std")]
+      check:     false
+    }
+  }
+}
+
+impl<Input, Output, Stack, Return> SendGenerator<Input, Output, Stack, Return>
+    where Stack: stack::Stack {
+  /// Enables or disables the migration check. While it is enabled, resuming the generator
+  /// on a different thread than the one it was last resumed on panics, reporting both threads.
+  #[cfg(feature = "std")]
+  pub fn set_migration_check(&mut self, check: bool) {
+    self.check = check
+  }
+
+  #[inline(always)]
+  fn before_resume(&mut self) {
+    #[cfg(feature = "std")]
+    if self.check {
+      let current = thread::current().id();
+      match self.thread {
+        Some(previous) if previous != current =>
+          panic!("generator suspended on thread {:?} resumed on thread {:?}", previous, current),
+        _ => self.thread = Some(current)
+      }
+    }
+  }
+
+  /// Same as [`Generator::resume`](struct.Generator.html#method.resume).
+  #[inline]
+  pub fn resume(&mut self, input: Input) -> Option<Output> {
+    self.before_resume();
+    self.generator.resume(input)
+  }
+
+  /// Same as [`Generator::resume_state`](struct.Generator.html#method.resume_state).
+  #[inline]
+  pub fn resume_state(&mut self, input: Input) -> GeneratorState<Output, Return> {
+    self.before_resume();
+    self.generator.resume_state(input)
+  }
+
+  /// Same as [`Generator::throw`](struct.Generator.html#method.throw).
+  #[inline]
+  pub fn throw<E: Send + 'static>(&mut self, err: E) -> Option<Output> {
+    self.before_resume();
+    self.generator.throw(err)
+  }
+
+  /// Same as [`Generator::throw_state`](struct.Generator.html#method.throw_state).
+  #[inline]
+  pub fn throw_state<E: Send + 'static>(&mut self, err: E) -> GeneratorState<Output, Return> {
+    self.before_resume();
+    self.generator.throw_state(err)
+  }
+
+  /// Same as [`Generator::resume_catching`](struct.Generator.html#method.resume_catching).
+  #[cfg(feature = "std")]
+  #[inline]
+  pub fn resume_catching(&mut self, input: Input)
+                        -> Result<GeneratorState<Output, Return>, Box<dyn Any + Send>> {
+    self.before_resume();
+    self.generator.resume_catching(input)
+  }
+
+  /// Same as [`Generator::try_resume`](struct.Generator.html#method.try_resume).
+  #[cfg(feature = "std")]
+  #[inline]
+  pub fn try_resume(&mut self, input: Input) -> Result<Option<Output>, StackOverflow> {
+    self.before_resume();
+    self.generator.try_resume(input)
+  }
+
+  /// Same as [`Generator::cancel`](struct.Generator.html#method.cancel).
+  #[cfg(feature = "std")]
+  pub fn cancel(&mut self) {
+    self.before_resume();
+    self.generator.cancel()
+  }
+
+  /// Returns the state of the generator.
+  #[inline]
+  pub fn state(&self) -> State { self.generator.state() }
+
+  /// Same as [`Generator::unwrap`](struct.Generator.html#method.unwrap).
+  pub fn unwrap(self) -> Stack {
+    self.generator.unwrap()
+  }
+
+  /// Converts the generator back into a generator that cannot be sent to another thread.
+  pub fn into_inner(self) -> Generator<Input, Output, Stack, Return> {
+    self.generator
   }
 }
 
-impl<Output, Stack> Iterator for Generator<(), Output, Stack>
-    where Output: Send, Stack: stack::Stack {
+impl<Output, Stack, Return> Iterator for SendGenerator<(), Output, Stack, Return>
+    where Stack: stack::Stack {
   type Item = Output;
 
   fn next(&mut self) -> Option<Self::Item> { self.resume(()) }
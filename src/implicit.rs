@@ -0,0 +1,97 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Suspension without passing the yielder around.
+//!
+//! While a generator function that has called
+//! [`Yielder::make_current`](generator/struct.Yielder.html#method.make_current) runs,
+//! its yielder is recorded in a thread-local variable. Every switch into another
+//! context saves and clears that variable, and restores it once control comes back,
+//! so it only ever refers to the generator that is running on the current stack.
+
+use core::any::TypeId;
+use core::cell::Cell;
+
+use generator::Yielder;
+
+/// The yielder of the generator running on the current stack, and its type.
+type Current = Option<(*const (), TypeId)>;
+
+thread_local! {
+  static CURRENT: Cell<Current> = const { Cell::new(None) };
+}
+
+/// Records the yielder of the generator that is about to run on the current stack.
+#[inline(always)]
+pub(crate) fn enter(yielder: *const (), type_id: TypeId) {
+  CURRENT.with(|current| current.set(Some((yielder, type_id))))
+}
+
+/// Forgets the yielder before switching to another context, and returns it
+/// so that it can be restored once the other context switches back.
+#[inline(always)]
+pub(crate) fn leave() -> Current {
+  CURRENT.with(|current| current.replace(None))
+}
+
+/// Restores the yielder returned by `leave`.
+#[inline(always)]
+pub(crate) fn restore(saved: Current) {
+  CURRENT.with(|current| current.set(saved))
+}
+
+/// Returns the yielder of the generator running on the current stack, if it has
+/// made itself current and has input type `Input` and output type `Output`.
+#[inline(always)]
+fn current<Input: 'static, Output: 'static>() -> Option<*const Yielder<Input, Output>> {
+  match CURRENT.with(|current| current.get()) {
+    Some((yielder, type_id)) if type_id == TypeId::of::<Yielder<Input, Output>>() =>
+      Some(yielder as *const Yielder<Input, Output>),
+    _ => None
+  }
+}
+
+/// Suspends the generator running on the current stack, as if `suspend(item)` was called
+/// on its yielder, and returns the input it is resumed with.
+///
+/// Only the innermost generator is considered; code running in a generator cannot
+/// suspend the generators that have resumed it. If the current stack is not
+/// a generator stack, or the generator function has not called `make_current()`,
+/// or its input and output types are not `Input` and `Output`, panics.
+///
+/// # Example
+///
+/// ```
+/// use fringe::{OsStack, Generator};
+/// use fringe::generator::Yielder;
+///
+/// fn visit(items: &[u32], callback: &mut dyn FnMut(u32)) {
+///   for &item in items { callback(item) }
+/// }
+///
+/// let stack = OsStack::new(0).unwrap();
+/// let generator = Generator::new(stack, |yielder: &mut Yielder<(), u32>, ()| {
+///   yielder.make_current();
+///   visit(&[1, 2, 3], &mut |item| fringe::suspend::<(), u32>(item * 2))
+/// });
+/// println!("{:?}", generator.collect::<Vec<_>>()); // prints [2, 4, 6]
+/// ```
+#[inline]
+pub fn suspend<Input: 'static, Output: 'static>(item: Output) -> Input {
+  match current::<Input, Output>() {
+    // The yielder lives in the bottom frame of the current stack.
+    Some(yielder) => unsafe { (*yielder).suspend(item) },
+    None => panic!("fringe::suspend called outside of a generator with matching types")
+  }
+}
+
+/// Returns `true` if [`suspend::<Input, Output>`](fn.suspend.html) would suspend
+/// the generator running on the current stack rather than panic.
+#[inline]
+pub fn can_suspend<Input: 'static, Output: 'static>() -> bool {
+  current::<Input, Output>().is_some()
+}
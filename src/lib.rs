@@ -4,10 +4,6 @@
 // http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
-#![feature(asm, naked_functions, cfg_target_vendor)]
-#![feature(never_type, slice_patterns)]
-#![cfg_attr(feature = "alloc", feature(alloc, heap_api))]
-#![cfg_attr(test, feature(test))]
 #![no_std]
 
 //! libfringe is a library implementing safe, lightweight context switches,
@@ -17,7 +13,15 @@
 //! It provides the following safe abstractions:
 //!
 //!   * an implementation of generators,
-//!     [Generator](generator/struct.Generator.html).
+//!     [Generator](generator/struct.Generator.html), and of generators
+//!     that can be moved between threads,
+//!     [SendGenerator](generator/struct.SendGenerator.html);
+//!   * symmetric coroutines that transfer control directly to each other,
+//!     [Coroutine](coroutine/struct.Coroutine.html);
+//!   * a scope for generators that borrow data from the caller,
+//!     [scope](fn.scope.html);
+//!   * suspension of the current generator from code it calls, without passing
+//!     the yielder around, [suspend](fn.suspend.html).
 //!
 //! It also provides the necessary low-level building blocks:
 //!
@@ -28,38 +32,85 @@
 //!   * a stack allocator based on `Box<[u8]>`,
 //!     [OwnedStack](struct.OwnedStack.html);
 //!   * a stack allocator based on anonymous memory mappings with guard pages,
-//!     [OsStack](struct.OsStack.html).
+//!     [OsStack](struct.OsStack.html);
+//!   * pools that recycle such stacks instead of unmapping them,
+//!     [StackPool](struct.StackPool.html) and [SyncStackPool](struct.SyncStackPool.html);
+//!   * a wrapper that paints a stack to measure how much of it gets used,
+//!     [PaintedStack](struct.PaintedStack.html);
+//!   * symmetric switching between execution contexts,
+//!     [context::Context](context/struct.Context.html);
+//!   * a handler reporting generator stack overflows,
+//!     [overflow::install_handler](overflow/fn.install_handler.html).
 
-#[cfg(test)]
+#[cfg(any(test, feature = "std"))]
 #[macro_use]
 extern crate std;
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 pub use stack::Stack;
 pub use stack::GuardedStack;
+pub use stack::StackError;
 pub use slice_stack::SliceStack;
-pub use generator::Generator;
+pub use painted_stack::{PaintedStack, STACK_PAINT};
+#[cfg(unix)]
+pub use painted_stack::measure_stack_usage;
+pub use generator::{Generator, SendGenerator};
+pub use coroutine::Coroutine;
+#[cfg(feature = "std")]
+pub use implicit::{suspend, can_suspend};
+pub use scope::{scope, Scope, ScopedGenerator};
 
 #[cfg(feature = "alloc")]
 pub use owned_stack::OwnedStack;
 
 #[cfg(unix)]
 pub use os::Stack as OsStack;
+#[cfg(unix)]
+pub use os::Builder as OsStackBuilder;
+#[cfg(unix)]
+pub use stack_pool::{StackPool, SyncStackPool, StackPoolBuilder, StackPoolStats, PooledStack, SyncPooledStack};
 
 mod arch;
 
 /// Minimum alignment of a stack base address on the target platform.
 pub const STACK_ALIGNMENT: usize = arch::STACK_ALIGNMENT;
 
+/// Minimum size of a stack a generator can run on, on the target platform.
+/// This leaves enough room for the panic machinery, but not much else.
+pub const MIN_STACK_SIZE: usize = arch::MIN_STACK_SIZE;
+
+/// Size of a stack that is comfortably large for most generators on the target platform.
+/// This is the size of `OsStack::new(0)`.
+pub const RECOMMENDED_STACK_SIZE: usize = arch::RECOMMENDED_STACK_SIZE;
+
+/// Minimum size of the guard of a stack a generator can run on, on the target platform.
+/// On targets where functions probe the pages of large stack frames, this is a single page;
+/// on other targets, a function whose frame is larger than the guard can skip over it.
+pub const MIN_GUARD_SIZE: usize = arch::MIN_GUARD_SIZE;
+
 mod debug;
 mod stack_pointer;
 
 mod fat_args;
 mod stack;
 mod slice_stack;
+mod painted_stack;
 pub mod generator;
+pub mod coroutine;
+mod scope;
+pub mod context;
+#[cfg(feature = "std")]
+mod implicit;
 
 #[cfg(feature = "alloc")]
 mod owned_stack;
 
 #[cfg(unix)]
 mod os;
+#[cfg(unix)]
+mod stack_pool;
+
+#[cfg(all(unix, feature = "std"))]
+pub mod overflow;
@@ -5,17 +5,24 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 extern crate std;
-use self::std::io::Error as IoError;
+use core::cmp;
+pub use self::std::io::Error as IoError;
+use self::std::io::ErrorKind;
 use stack;
 
 mod sys;
 
+// Used by the overflow handler.
+#[cfg(feature = "std")]
+pub use self::sys::{map_stack, unmap_stack};
+
 /// OsStack holds a guarded stack allocated using the operating system's anonymous
 /// memory mapping facility.
 #[derive(Debug)]
 pub struct Stack {
-  ptr: *mut u8,
-  len: usize
+  ptr:   *mut u8,
+  len:   usize,
+  guard: usize
 }
 
 unsafe impl Send for Stack {}
@@ -23,52 +30,171 @@ unsafe impl Send for Stack {}
 impl Stack {
   /// Allocates a new stack with at least `size` accessible bytes.
   /// `size` is rounded up to an integral number of pages; `Stack::new(0)` is legal
-  /// and allocates the smallest possible stack, consisting of one data page and
-  /// one guard page.
+  /// and allocates a stack of [`RECOMMENDED_STACK_SIZE`](constant.RECOMMENDED_STACK_SIZE.html)
+  /// bytes, followed by as many guard pages as it takes to cover
+  /// [`MIN_GUARD_SIZE`](constant.MIN_GUARD_SIZE.html) bytes, which is one on most targets.
   pub fn new(size: usize) -> Result<Stack, IoError> {
+    Stack::builder().size(size).build()
+  }
+
+  /// Returns a builder for a stack with a custom size and number of guard pages.
+  ///
+  /// # Example
+  ///
+  /// ```
+  /// use fringe::{OsStack, GuardedStack};
+  ///
+  /// let stack = OsStack::builder().size(1 << 20).guard_pages(4).build().unwrap();
+  /// println!("{}", stack.guard_size()); // prints 16384 with 4096-byte pages
+  /// ```
+  pub fn builder() -> Builder {
+    Builder { size: 0, guard_pages: None, lazy: false }
+  }
+
+  /// Returns the number of bytes of address space reserved for the stack,
+  /// i.e. `base() - limit()`.
+  pub fn reserved_size(&self) -> usize {
+    self.len - self.guard
+  }
+
+  /// Returns the number of bytes of the stack that are backed by physical memory,
+  /// as reported by `mincore`.
+  pub fn resident_size(&self) -> Result<usize, IoError> {
+    unsafe { sys::resident_size(self.ptr.add(self.guard), self.reserved_size()) }
+  }
+
+  /// Makes the topmost `size` bytes of the stack, rounded up to an integral number of pages,
+  /// resident, so that a generator function using no more than that never faults them in.
+  /// The contents of the stack are left intact. If `size` cannot be rounded up, returns
+  /// an error of kind `InvalidInput`.
+  pub fn commit(&mut self, size: usize) -> Result<(), IoError> {
+    let size = round_to_pages(size).ok_or_else(too_large)?;
+    let size = cmp::min(size, self.reserved_size());
+    unsafe { sys::commit_stack(self.ptr.add(self.len - size), size) }
+  }
+
+  /// Returns every page of the stack to the operating system, which then only takes up
+  /// address space until it is touched again. The contents of the stack are lost.
+  pub fn decommit(&mut self) -> Result<(), IoError> {
+    unsafe { sys::decommit_stack(self.ptr.add(self.guard), self.reserved_size()) }
+  }
+
+  /// Returns the pages of the stack that lie entirely between `start` and `end`
+  /// to the operating system, and returns their size. Anything outside of the stack
+  /// is left alone.
+  ///
+  /// Nothing on the stack between `start` and `end` may be used again.
+  pub(crate) unsafe fn decommit_range(&self, start: usize, end: usize) -> Result<usize, IoError> {
+    let start = cmp::max(start, self.ptr as usize + self.guard);
+    let end = cmp::min(end, self.ptr as usize + self.len);
     let page_size = sys::page_size();
+    let start = (start + page_size - 1) & !(page_size - 1);
+    let end = end & !(page_size - 1);
+    if start >= end { return Ok(0) }
+    sys::decommit_stack(start as *mut u8, end - start)?;
+    Ok(end - start)
+  }
+}
 
-    // Stacks have to be at least one page long.
-    let len = if size == 0 { page_size } else { size };
+/// Builder configures an [`OsStack`](struct.OsStack.html) before allocating it.
+#[derive(Debug, Clone)]
+pub struct Builder {
+  size:        usize,
+  guard_pages: Option<usize>,
+  lazy:        bool
+}
 
-    // Round the length one page size up, using the fact that the page size
-    // is a power of two.
-    let len = (len + page_size - 1) & !(page_size - 1);
+impl Builder {
+  /// Sets the number of accessible bytes of the stack, as in
+  /// [`OsStack::new`](struct.OsStack.html#method.new). The default is 0.
+  pub fn size(self, size: usize) -> Builder {
+    Builder { size, ..self }
+  }
 
-    // Increase the length to fit the guard page.
-    let len = len + page_size;
+  /// Sets the number of guard pages below the stack. The default is as many as it takes
+  /// to cover [`MIN_GUARD_SIZE`](constant.MIN_GUARD_SIZE.html) bytes. A generator function
+  /// with a frame larger than the guard can skip over it on targets without stack probes,
+  /// so it needs more guard pages there.
+  pub fn guard_pages(self, guard_pages: usize) -> Builder {
+    Builder { guard_pages: Some(guard_pages), ..self }
+  }
+
+  /// Sets whether the stack only reserves address space, so that memory is only accounted for
+  /// once it is touched, and stacks of several megabytes cost little more than small ones
+  /// as long as they stay shallow. The default is `false`.
+  ///
+  /// On Linux, this maps the stack with `MAP_NORESERVE`; other platforms already treat
+  /// every stack this way.
+  pub fn lazy(self, lazy: bool) -> Builder {
+    Builder { lazy, ..self }
+  }
+
+  /// Allocates the stack. If no guard pages were asked for, or the size of the stack
+  /// together with its guard pages does not fit in the address space, returns an error
+  /// of kind `InvalidInput`.
+  pub fn build(self) -> Result<Stack, IoError> {
+    let page_size = sys::page_size();
+
+    let len = if self.size == 0 { ::RECOMMENDED_STACK_SIZE } else { self.size };
+    let len = round_to_pages(len).ok_or_else(too_large)?;
+
+    let guard_pages = self.guard_pages.unwrap_or(::MIN_GUARD_SIZE.div_ceil(page_size));
+    if guard_pages == 0 {
+      return Err(IoError::new(ErrorKind::InvalidInput, "stack must have at least one guard page"))
+    }
+    let guard = guard_pages.checked_mul(page_size).ok_or_else(too_large)?;
+
+    // Increase the length to fit the guard pages.
+    let len = len.checked_add(guard).ok_or_else(too_large)?;
 
     // Allocate a stack.
     let stack = Stack {
-      ptr: try!(unsafe { sys::map_stack(len) }),
-      len: len
+      ptr: unsafe { sys::map_stack(len, self.lazy)? },
+      len,
+      guard
     };
 
-    // Mark the guard page. If this fails, `stack` will be dropped,
+    // Mark the guard pages. If this fails, `stack` will be dropped,
     // unmapping it.
-    try!(unsafe { sys::protect_stack(stack.ptr) });
+    unsafe { sys::protect_stack(stack.ptr, guard)? };
 
     Ok(stack)
   }
 }
 
+/// Rounds `size` up to an integral number of pages, using the fact that the page size
+/// is a power of two. Returns `None` if the result does not fit in a `usize`.
+fn round_to_pages(size: usize) -> Option<usize> {
+  let page_size = sys::page_size();
+  size.checked_add(page_size - 1).map(|size| size & !(page_size - 1))
+}
+
+fn too_large() -> IoError {
+  IoError::new(ErrorKind::InvalidInput, "stack does not fit in the address space")
+}
+
 impl stack::Stack for Stack {
   #[inline(always)]
   fn base(&self) -> *mut u8 {
     unsafe {
-      self.ptr.offset(self.len as isize)
+      self.ptr.add(self.len)
     }
   }
 
   #[inline(always)]
   fn limit(&self) -> *mut u8 {
     unsafe {
-      self.ptr.offset(sys::page_size() as isize)
+      self.ptr.add(self.guard)
     }
   }
 }
 
-unsafe impl stack::GuardedStack for Stack {}
+unsafe impl stack::GuardedStack for Stack {
+  #[inline(always)]
+  fn guard_size(&self) -> usize {
+    self.guard
+  }
+}
 
 impl Drop for Stack {
   fn drop(&mut self) {
@@ -7,7 +7,7 @@
 extern crate std;
 extern crate libc;
 
-use self::std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+use self::std::sync::atomic::{AtomicUsize, Ordering};
 use self::std::ptr;
 use self::std::io::Error as IoError;
 use self::libc::{c_void, c_int, size_t};
@@ -27,8 +27,17 @@ const STACK_FLAGS: c_int = libc::MAP_STACK
 const STACK_FLAGS: c_int = libc::MAP_PRIVATE
                          | libc::MAP_ANON;
 
-pub unsafe fn map_stack(len: usize) -> Result<*mut u8, IoError> {
-  let ptr = mmap(ptr::null_mut(), len as size_t, STACK_PROT, STACK_FLAGS, -1, 0);
+// Only reserve address space for a lazy stack, without accounting for its pages
+// until they are touched.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const LAZY_FLAGS:  c_int = libc::MAP_NORESERVE;
+// Other platforms never account for the pages of anonymous mappings up front.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const LAZY_FLAGS:  c_int = 0;
+
+pub unsafe fn map_stack(len: usize, lazy: bool) -> Result<*mut u8, IoError> {
+  let flags = if lazy { STACK_FLAGS | LAZY_FLAGS } else { STACK_FLAGS };
+  let ptr = mmap(ptr::null_mut(), len as size_t, STACK_PROT, flags, -1, 0);
   if ptr == MAP_FAILED {
     Err(IoError::last_os_error())
   } else {
@@ -36,8 +45,8 @@ pub unsafe fn map_stack(len: usize) -> Result<*mut u8, IoError> {
   }
 }
 
-pub unsafe fn protect_stack(ptr: *mut u8) -> Result<(), IoError> {
-  if mprotect(ptr as *mut c_void, page_size() as size_t, GUARD_PROT) == 0 {
+pub unsafe fn protect_stack(ptr: *mut u8, len: usize) -> Result<(), IoError> {
+  if mprotect(ptr as *mut c_void, len as size_t, GUARD_PROT) == 0 {
     Ok(())
   } else {
     Err(IoError::last_os_error())
@@ -52,6 +61,45 @@ pub unsafe fn unmap_stack(ptr: *mut u8, len: usize) -> Result<(), IoError> {
   }
 }
 
+/// Makes the pages in `ptr..ptr+len` resident without changing their contents.
+pub unsafe fn commit_stack(ptr: *mut u8, len: usize) -> Result<(), IoError> {
+  // Linux 5.14 and later can populate the pages at once, and report running out of memory
+  // rather than killing the process.
+  #[cfg(any(target_os = "linux", target_os = "android"))] {
+    if len == 0 || libc::madvise(ptr as *mut c_void, len as size_t, libc::MADV_POPULATE_WRITE) == 0 {
+      return Ok(())
+    }
+    let err = IoError::last_os_error();
+    if err.raw_os_error() != Some(libc::EINVAL) { return Err(err) }
+  }
+  // Otherwise, write to each of them.
+  let mut page = ptr;
+  while page < ptr.add(len) {
+    ptr::write_volatile(page, ptr::read_volatile(page));
+    page = page.add(page_size())
+  }
+  Ok(())
+}
+
+/// Returns the pages in `ptr..ptr+len` to the operating system; their contents are lost.
+pub unsafe fn decommit_stack(ptr: *mut u8, len: usize) -> Result<(), IoError> {
+  if len == 0 || libc::madvise(ptr as *mut c_void, len as size_t, libc::MADV_DONTNEED) == 0 {
+    Ok(())
+  } else {
+    Err(IoError::last_os_error())
+  }
+}
+
+/// Returns the number of bytes of the pages in `ptr..ptr+len` that are resident.
+pub unsafe fn resident_size(ptr: *mut u8, len: usize) -> Result<usize, IoError> {
+  let mut pages = std::vec![0u8; len.div_ceil(page_size())];
+  if libc::mincore(ptr as *mut c_void, len as size_t, pages.as_mut_ptr() as *mut _) == 0 {
+    Ok(pages.iter().filter(|&&page| page & 1 != 0).count() * page_size())
+  } else {
+    Err(IoError::last_os_error())
+  }
+}
+
 pub fn page_size() -> usize {
   #[cold]
   pub fn sys_page_size() -> usize {
@@ -60,7 +108,7 @@ pub fn page_size() -> usize {
     }
   }
 
-  static PAGE_SIZE_CACHE: AtomicUsize = ATOMIC_USIZE_INIT;
+  static PAGE_SIZE_CACHE: AtomicUsize = AtomicUsize::new(0);
   match PAGE_SIZE_CACHE.load(Ordering::Relaxed) {
     0 => {
       let page_size = sys_page_size();
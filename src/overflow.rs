@@ -0,0 +1,435 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reporting of generator stack overflows.
+//!
+//! When a generator function overflows its stack, it accesses the guard page below
+//! the stack limit, and the operating system delivers `SIGSEGV` (or `SIGBUS`, on some
+//! platforms) to the thread, which kills the process without any explanation.
+//! The handler installed by `install_handler()` recognizes accesses to the guard pages
+//! of generator stacks, reports which generator has overflowed its stack, and aborts.
+//! Any other signal is passed on to the handler that was installed before it.
+//!
+//! Alternatively, after `set_recoverable(true)`, the handler abandons the generator
+//! that has overflowed its stack and returns control to its resumer, which receives
+//! `Err(StackOverflow)` from `Generator::try_resume`.
+extern crate std;
+extern crate libc;
+
+use core::{cmp, fmt, mem, ptr, slice, str};
+use self::std::boxed::Box;
+use self::std::cell::RefCell;
+use self::std::io::Error as IoError;
+use self::std::sync::{Mutex, OnceLock, PoisonError};
+use self::std::sync::atomic::{self, AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use self::libc::{c_int, c_void, siginfo_t, sigaction, stack_t};
+
+use stack::Stack;
+use stack_pointer::StackPointer;
+use generator::OVERFLOWED;
+use arch;
+use os;
+
+/// Installs a handler for `SIGSEGV` and `SIGBUS` that reports generator stack overflows,
+/// and sets up an alternate signal stack for the calling thread, if it does not have one.
+///
+/// The handler runs on the alternate signal stack, since the stack that has overflowed
+/// cannot be used for that. The Rust runtime sets one up for the main thread and for every
+/// thread started using `std::thread`, so this function only has to be called once; threads
+/// started by other means have to call it before running any generators.
+///
+/// Only the stacks of generators created using `Generator::new` or `Generator::try_new`
+/// after this function is called are recognized; `Generator::unsafe_new` does not require
+/// a guard page, so its stacks are never recognized.
+pub fn install_handler() -> Result<(), IoError> {
+  install_alt_stack()?;
+
+  static INSTALL_LOCK: Mutex<()> = Mutex::new(());
+  let _lock = INSTALL_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+  if INSTALLED.load(Ordering::Acquire) { return Ok(()) }
+
+  unsafe {
+    let mut action: sigaction = mem::zeroed();
+    action.sa_sigaction = handler as *const () as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    libc::sigemptyset(&mut action.sa_mask);
+
+    let mut previous: [sigaction; 2] = mem::zeroed();
+    for index in 0..SIGNALS.len() {
+      if libc::sigaction(SIGNALS[index], &action, &mut previous[index]) != 0 {
+        let err = IoError::last_os_error();
+        // Put back the handlers that have already been replaced.
+        for (&signum, previous) in SIGNALS.iter().zip(previous.iter()).take(index) {
+          libc::sigaction(signum, previous, ptr::null_mut());
+        }
+        return Err(err)
+      }
+    }
+    let _ = PREVIOUS.set(Previous(previous));
+  }
+
+  INSTALLED.store(true, Ordering::Release);
+  Ok(())
+}
+
+/// Chooses what the handler does once a generator overflows its stack. If `recoverable`
+/// is `false`, which is the default, it reports the overflow and aborts the process.
+/// If `recoverable` is `true`, it abandons the generator function without unwinding its
+/// stack and returns control to the resumer; the generator is then `State::Poisoned`.
+///
+/// Since the stack of the generator function is not unwound, every value it holds is leaked.
+/// In particular, any locks it holds are never released.
+///
+/// Recovery is supported on Linux on x86, x86_64 and aarch64; elsewhere, overflows are
+/// reported regardless of this setting.
+pub fn set_recoverable(recoverable: bool) {
+  RECOVERABLE.store(recoverable, Ordering::Relaxed)
+}
+
+const SIGNALS: [c_int; 2] = [libc::SIGSEGV, libc::SIGBUS];
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static RECOVERABLE: AtomicBool = AtomicBool::new(false);
+
+/// The handlers that were installed before ours, in the same order as `SIGNALS`.
+struct Previous([sigaction; 2]);
+
+// The handlers are only ever read, and `sigaction` is plain old data.
+unsafe impl Send for Previous {}
+unsafe impl Sync for Previous {}
+
+static PREVIOUS: OnceLock<Previous> = OnceLock::new();
+
+/// A guarded stack that is currently in use by a generator.
+#[derive(Clone, Copy)]
+struct Entry {
+  guard_start: usize,
+  guard_end:   usize,
+  base:        usize,
+  name:        &'static str
+}
+
+/// A slot of the registry, which holds an entry while it is used by a registration.
+///
+/// The signal handler cannot take a lock, since the thread it interrupts may hold it,
+/// so the registry is a list of slots that are never freed, which the handler walks
+/// without synchronizing with the registrations. Slots are reused once their registration
+/// is dropped, and every slot is a seqlock, so that the handler never sees an entry
+/// that is only partially written.
+#[derive(Debug)]
+struct Slot {
+  /// Odd while the entry is being written.
+  seq:         AtomicUsize,
+  used:        AtomicBool,
+  guard_start: AtomicUsize,
+  guard_end:   AtomicUsize,
+  base:        AtomicUsize,
+  name_ptr:    AtomicPtr<u8>,
+  name_len:    AtomicUsize,
+  /// The next slot of the registry; never changes once the slot is in the registry.
+  next:        *const Slot
+}
+
+// The fields that change are atomic, and the slot is never freed.
+unsafe impl Send for Slot {}
+unsafe impl Sync for Slot {}
+
+/// The number of times the signal handler tries to read an entry that is being written,
+/// before it skips it. An entry is only ever written outside of a generator, so it is not
+/// the one of the generator that has overflowed its stack; however, the write may have been
+/// interrupted by the signal, so the handler cannot wait for it.
+const READ_ATTEMPTS: usize = 100;
+
+impl Slot {
+  /// Writes `entry` to the slot. Only the owner of the slot may write to it.
+  fn write(&self, entry: Entry) {
+    let seq = self.seq.load(Ordering::Relaxed);
+    self.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+    atomic::fence(Ordering::Release);
+    self.guard_start.store(entry.guard_start, Ordering::Relaxed);
+    self.guard_end.store(entry.guard_end, Ordering::Relaxed);
+    self.base.store(entry.base, Ordering::Relaxed);
+    self.name_ptr.store(entry.name.as_ptr() as *mut u8, Ordering::Relaxed);
+    self.name_len.store(entry.name.len(), Ordering::Relaxed);
+    self.seq.store(seq.wrapping_add(2), Ordering::Release);
+  }
+
+  /// Reads the entry in the slot, or returns `None` if it keeps being written.
+  fn read(&self) -> Option<Entry> {
+    for _ in 0..READ_ATTEMPTS {
+      let seq = self.seq.load(Ordering::Acquire);
+      if seq % 2 == 1 { continue }
+      let guard_start = self.guard_start.load(Ordering::Relaxed);
+      let guard_end   = self.guard_end.load(Ordering::Relaxed);
+      let base        = self.base.load(Ordering::Relaxed);
+      let name_ptr    = self.name_ptr.load(Ordering::Relaxed);
+      let name_len    = self.name_len.load(Ordering::Relaxed);
+      atomic::fence(Ordering::Acquire);
+      if self.seq.load(Ordering::Relaxed) != seq { continue }
+      // The name was written together with the rest of the entry, so it is a whole `&'static str`.
+      let name = unsafe { str::from_utf8_unchecked(slice::from_raw_parts(name_ptr, name_len)) };
+      return Some(Entry { guard_start, guard_end, base, name })
+    }
+    None
+  }
+}
+
+/// An entry that matches no address, written to a slot that is not used.
+const UNUSED: Entry = Entry { guard_start: 0, guard_end: 0, base: 0, name: "" };
+
+/// The first slot of the registry, or null.
+static REGISTRY: AtomicPtr<Slot> = AtomicPtr::new(ptr::null_mut());
+
+/// A registration of a guarded stack with the overflow handler, removed on drop.
+#[derive(Debug)]
+pub(crate) struct Registration(&'static Slot);
+
+impl Registration {
+  /// Registers the guard of `guard_size` bytes below the limit of `stack`, on which
+  /// a generator called `name` is running. If the handler is not installed, does nothing.
+  pub fn register(stack: &dyn Stack, guard_size: usize, name: &'static str) -> Option<Registration> {
+    if !INSTALLED.load(Ordering::Acquire) { return None }
+
+    let limit = stack.limit() as usize;
+    let entry = Entry {
+      guard_start: limit.saturating_sub(guard_size),
+      guard_end:   limit,
+      base:        stack.base() as usize,
+      name
+    };
+    let slot = Registration::claim();
+    slot.write(entry);
+    Some(Registration(slot))
+  }
+
+  /// Claims a slot that is not used, adding one to the registry if there is none.
+  fn claim() -> &'static Slot {
+    let mut head = REGISTRY.load(Ordering::Acquire);
+    let mut slot = head as *const Slot;
+    while let Some(candidate) = unsafe { slot.as_ref() } {
+      if candidate.used.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+        return candidate
+      }
+      slot = candidate.next
+    }
+
+    let slot = Box::leak(Box::new(Slot {
+      seq:         AtomicUsize::new(0),
+      used:        AtomicBool::new(true),
+      guard_start: AtomicUsize::new(0),
+      guard_end:   AtomicUsize::new(0),
+      base:        AtomicUsize::new(0),
+      name_ptr:    AtomicPtr::new(ptr::null_mut()),
+      name_len:    AtomicUsize::new(0),
+      next:        ptr::null()
+    }));
+    loop {
+      slot.next = head;
+      match REGISTRY.compare_exchange_weak(head, slot, Ordering::Release, Ordering::Acquire) {
+        Ok(_) => return slot,
+        Err(current) => head = current
+      }
+    }
+  }
+
+  /// Records that a generator called `name` is now running on the registered stack,
+  /// whose base has moved to that of `stack`.
+  pub fn update(&self, stack: &dyn Stack, name: &'static str) {
+    // Only the registration writes to its slot, so the guard can be read as is.
+    self.0.write(Entry {
+      guard_start: self.0.guard_start.load(Ordering::Relaxed),
+      guard_end:   self.0.guard_end.load(Ordering::Relaxed),
+      base:        stack.base() as usize,
+      name
+    })
+  }
+}
+
+impl Drop for Registration {
+  fn drop(&mut self) {
+    self.0.write(UNUSED);
+    self.0.used.store(false, Ordering::Release)
+  }
+}
+
+/// Finds the stack whose guard page contains `addr`. Since this is called from
+/// the signal handler, it does not wait for entries that are being written.
+fn lookup(addr: usize) -> Option<Entry> {
+  let mut slot = REGISTRY.load(Ordering::Acquire) as *const Slot;
+  while let Some(current) = unsafe { slot.as_ref() } {
+    match current.read() {
+      Some(entry) if entry.guard_start <= addr && addr < entry.guard_end => return Some(entry),
+      _ => slot = current.next
+    }
+  }
+  None
+}
+
+unsafe extern "C" fn handler(signum: c_int, info: *mut siginfo_t, context: *mut c_void) {
+  let addr = (*info).si_addr() as usize;
+  match lookup(addr) {
+    Some(entry) => {
+      if RECOVERABLE.load(Ordering::Relaxed) && recover(&entry, context) { return }
+      report(&entry);
+      libc::abort()
+    }
+    None => chain(signum, info, context)
+  }
+}
+
+/// Continues execution of the thread, once the handler returns, in `abandon` on top
+/// of the stack that has overflowed, which is free to reuse since nothing on it will
+/// ever run again.
+#[cfg(target_os = "linux")]
+unsafe fn recover(entry: &Entry, context: *mut c_void) -> bool {
+  // The resumer of the generator left its stack pointer in the CFA slot
+  // when it switched to the generator stack.
+  let resumer_sp = *(entry.base as *const usize).offset(arch::CFA_SLOT);
+  let sp = (entry.base - 1024) & !(arch::STACK_ALIGNMENT - 1);
+  let pc = abandon as *const () as usize;
+  let mcontext = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext;
+
+  #[cfg(target_arch = "x86_64")] {
+    // Simulate a call; the return address is never used.
+    mcontext.gregs[libc::REG_RSP as usize] = (sp - 8) as libc::greg_t;
+    mcontext.gregs[libc::REG_RIP as usize] = pc as libc::greg_t;
+    mcontext.gregs[libc::REG_RDI as usize] = resumer_sp as libc::greg_t;
+    true
+  }
+  #[cfg(target_arch = "x86")] {
+    // Simulate a call, which passes the argument on the stack; the return address
+    // is never used.
+    let sp = sp - 20;
+    *((sp + 4) as *mut usize) = resumer_sp;
+    mcontext.gregs[libc::REG_ESP as usize] = sp as libc::greg_t;
+    mcontext.gregs[libc::REG_EIP as usize] = pc as libc::greg_t;
+    true
+  }
+  #[cfg(target_arch = "aarch64")] {
+    mcontext.sp      = sp as _;
+    mcontext.pc      = pc as _;
+    mcontext.regs[0] = resumer_sp as _;
+    true
+  }
+  #[cfg(not(any(target_arch = "x86_64", target_arch = "x86", target_arch = "aarch64")))] {
+    let _ = (resumer_sp, sp, pc, mcontext);
+    false
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+unsafe fn recover(_entry: &Entry, _context: *mut c_void) -> bool {
+  false
+}
+
+/// Switches to the resumer of the generator that has overflowed its stack,
+/// as if the generator had suspended itself, and never returns.
+unsafe extern "C" fn abandon(resumer_sp: usize) -> ! {
+  StackPointer::swap(OVERFLOWED, StackPointer(resumer_sp as *mut usize), None);
+  // The generator is poisoned, so the resumer never switches back.
+  libc::abort()
+}
+
+/// Writes the report directly to the standard error; nothing else is safe to use
+/// in a signal handler.
+fn report(entry: &Entry) {
+  let mut buffer = Buffer { bytes: [0; 512], len: 0 };
+  let _ = fmt::Write::write_fmt(&mut buffer, format_args!(
+    "\ngenerator `{}` has overflowed its stack ({:#x}-{:#x})\n\
+     fatal runtime error: generator stack overflow, aborting\n",
+    entry.name, entry.guard_end, entry.base));
+  unsafe { libc::write(libc::STDERR_FILENO, buffer.bytes.as_ptr() as *const c_void, buffer.len) };
+}
+
+/// A fixed-size buffer that silently truncates whatever does not fit.
+struct Buffer {
+  bytes: [u8; 512],
+  len:   usize
+}
+
+impl fmt::Write for Buffer {
+  fn write_str(&mut self, s: &str) -> fmt::Result {
+    let len = cmp::min(s.len(), self.bytes.len() - self.len);
+    self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+    self.len += len;
+    Ok(())
+  }
+}
+
+/// Passes the signal on to the handler that was installed before ours.
+unsafe fn chain(signum: c_int, info: *mut siginfo_t, context: *mut c_void) {
+  let previous = PREVIOUS.get().and_then(|previous| {
+    SIGNALS.iter().position(|&s| s == signum).map(|index| previous.0[index])
+  });
+
+  match previous {
+    Some(action) if action.sa_sigaction != libc::SIG_DFL && action.sa_sigaction != libc::SIG_IGN => {
+      if action.sa_flags & libc::SA_SIGINFO != 0 {
+        let f: unsafe extern "C" fn(c_int, *mut siginfo_t, *mut c_void) = mem::transmute(action.sa_sigaction);
+        f(signum, info, context)
+      } else {
+        let f: unsafe extern "C" fn(c_int) = mem::transmute(action.sa_sigaction);
+        f(signum)
+      }
+    }
+    _ => {
+      // Restore the default action; returning from the handler retries the faulting
+      // instruction, which then terminates the process as usual.
+      let mut action: sigaction = mem::zeroed();
+      action.sa_sigaction = libc::SIG_DFL;
+      libc::sigaction(signum, &action, ptr::null_mut());
+    }
+  }
+}
+
+/// An alternate signal stack allocated by us, freed when its thread exits.
+struct AltStack {
+  ptr: *mut u8,
+  len: usize
+}
+
+impl Drop for AltStack {
+  fn drop(&mut self) {
+    unsafe {
+      let mut disable: stack_t = mem::zeroed();
+      disable.ss_flags = libc::SS_DISABLE;
+      libc::sigaltstack(&disable, ptr::null_mut());
+      let _ = os::unmap_stack(self.ptr, self.len);
+    }
+  }
+}
+
+thread_local! {
+  static ALT_STACK: RefCell<Option<AltStack>> = const { RefCell::new(None) };
+}
+
+fn install_alt_stack() -> Result<(), IoError> {
+  ALT_STACK.with(|alt_stack| {
+    if alt_stack.borrow().is_some() { return Ok(()) }
+
+    unsafe {
+      let mut current: stack_t = mem::zeroed();
+      if libc::sigaltstack(ptr::null(), &mut current) != 0 {
+        return Err(IoError::last_os_error())
+      }
+      // Someone else, most likely the Rust runtime, has already set one up.
+      if current.ss_flags & libc::SS_DISABLE == 0 { return Ok(()) }
+
+      let len = cmp::max(libc::SIGSTKSZ, 64 * 1024);
+      let stack = AltStack { ptr: os::map_stack(len, false)?, len };
+      let mut new: stack_t = mem::zeroed();
+      new.ss_sp    = stack.ptr as *mut c_void;
+      new.ss_size  = stack.len;
+      new.ss_flags = 0;
+      if libc::sigaltstack(&new, ptr::null_mut()) != 0 {
+        return Err(IoError::last_os_error())
+      }
+      *alt_stack.borrow_mut() = Some(stack);
+    }
+    Ok(())
+  })
+}
@@ -1,38 +1,55 @@
 // This file is part of libfringe, a low-level green threading library.
 // Copyright (c) whitequark <whitequark@whitequark.org>
 // See the LICENSE file included in this distribution.
-extern crate alloc;
-
-use core::slice;
-use self::alloc::heap;
-use self::alloc::boxed::Box;
+use alloc::alloc::{alloc, dealloc, handle_alloc_error, Layout};
 
 /// OwnedStack holds a non-guarded, heap-allocated stack.
 #[derive(Debug)]
-pub struct OwnedStack(Box<[u8]>);
+pub struct OwnedStack {
+    ptr: *mut u8,
+    len: usize
+}
+
+unsafe impl Send for OwnedStack {}
+unsafe impl Sync for OwnedStack {}
 
 impl OwnedStack {
     /// Allocates a new stack with exactly `size` accessible bytes and alignment appropriate
     /// for the current platform using the default Rust allocator.
     pub fn new(size: usize) -> OwnedStack {
-        unsafe {
-            let ptr = heap::allocate(size, ::STACK_ALIGNMENT);
-            OwnedStack(Box::from_raw(slice::from_raw_parts_mut(ptr, size)))
+        // A zero-sized allocation is not allowed; such a stack cannot be used anyway.
+        if size == 0 {
+            return OwnedStack { ptr: ::STACK_ALIGNMENT as *mut u8, len: 0 }
         }
+
+        let layout = OwnedStack::layout(size);
+        let ptr = unsafe { alloc(layout) };
+        if ptr.is_null() { handle_alloc_error(layout) }
+        OwnedStack { ptr, len: size }
+    }
+
+    fn layout(size: usize) -> Layout {
+        Layout::from_size_align(size, ::STACK_ALIGNMENT).expect("stack too large")
     }
 }
 
 impl ::stack::Stack for OwnedStack {
     #[inline(always)]
     fn base(&self) -> *mut u8 {
-        // The slice cannot wrap around the address space, so the conversion from usize
-        // to isize will not wrap either.
-        let len: isize = self.0.len() as isize;
-        unsafe { self.limit().offset(len) }
+        // The allocation cannot wrap around the address space, so this cannot overflow.
+        unsafe { self.ptr.add(self.len) }
     }
 
     #[inline(always)]
     fn limit(&self) -> *mut u8 {
-        self.0.as_ptr() as *mut u8
+        self.ptr
+    }
+}
+
+impl Drop for OwnedStack {
+    fn drop(&mut self) {
+        if self.len != 0 {
+            unsafe { dealloc(self.ptr, OwnedStack::layout(self.len)) }
+        }
     }
 }
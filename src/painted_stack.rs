@@ -0,0 +1,173 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use core::{ptr, slice};
+
+use stack;
+use debug;
+use slice_stack::SliceStack;
+#[cfg(feature = "alloc")]
+use owned_stack::OwnedStack;
+#[cfg(unix)]
+use os::Stack as OsStack;
+#[cfg(unix)]
+use generator::{GeneratorState, Yielder};
+
+/// The byte a painted stack is filled with.
+pub const STACK_PAINT: u8 = 0xa5;
+
+/// PaintedStack holds a stack of any kind that was filled with
+/// [`STACK_PAINT`](constant.STACK_PAINT.html) when it was created, so that it can
+/// later tell how much of it has been used.
+///
+/// Painting writes to every page of the stack, so an `OsStack` no longer only uses
+/// as much memory as has been touched by the code running on it. It is meant
+/// for measuring how large stacks need to be, e.g. in tests.
+///
+/// An `OsStack`, `OwnedStack` or `SliceStack` is painted with `PaintedStack::from`;
+/// any other stack has to be painted with the unsafe `PaintedStack::new`.
+///
+/// # Example
+///
+/// ```
+/// use fringe::{OsStack, PaintedStack, Generator};
+///
+/// let stack = PaintedStack::from(OsStack::new(0).unwrap());
+/// let mut generator = Generator::new(stack, |yielder, ()| {
+///   let buffer = [0u8; 4096];
+///   yielder.suspend(buffer.len())
+/// });
+/// generator.resume(());
+/// println!("{}", generator.stack_high_water()); // prints something above 4096
+/// ```
+#[derive(Debug)]
+pub struct PaintedStack<Stack> {
+  stack: Stack
+}
+
+impl<Stack: stack::Stack> PaintedStack<Stack> {
+  /// Fills `stack` with [`STACK_PAINT`](constant.STACK_PAINT.html).
+  ///
+  /// # Safety
+  ///
+  /// All of the memory between `stack.limit()` and `stack.base()` must be valid
+  /// for reads and writes, and must not be accessed through anything but the
+  /// returned `PaintedStack` for as long as it lives, since it is overwritten here
+  /// and later read by `high_water`.
+  pub unsafe fn new(stack: Stack) -> PaintedStack<Stack> {
+    let limit = stack.limit();
+    let size = stack.base() as usize - limit as usize;
+    ptr::write_bytes(limit, STACK_PAINT, size);
+    PaintedStack { stack }
+  }
+
+  /// Returns the number of bytes of the stack that have been used since it was painted,
+  /// counting from the base to the deepest byte that no longer holds the paint.
+  ///
+  /// A byte that has been overwritten with the paint itself is not noticed,
+  /// so the result may fall a few bytes short.
+  pub fn high_water(&self) -> usize {
+    self.used(0)
+  }
+
+  /// Same as `high_water`, but leaves out the header that the `hardening` feature
+  /// makes a generator write at the limit of its stack.
+  pub(crate) fn generator_high_water(&self) -> usize {
+    self.used(debug::HEADER_SIZE)
+  }
+
+  /// Returns the number of bytes used, not looking at the lowest `skip` bytes of the stack.
+  fn used(&self, skip: usize) -> usize {
+    let limit = self.stack.limit() as usize + skip;
+    let size = self.stack.base() as usize - limit;
+    // The bytes are valid for reads by the contract of `new`, and nothing writes
+    // to the stack while it is borrowed.
+    let bytes = unsafe { slice::from_raw_parts(limit as *const u8, size) };
+    match bytes.iter().position(|&byte| byte != STACK_PAINT) {
+      Some(unused) => size - unused,
+      None => 0
+    }
+  }
+
+  /// Extracts the stack.
+  pub fn into_inner(self) -> Stack {
+    self.stack
+  }
+}
+
+impl<Stack: stack::Stack> stack::Stack for PaintedStack<Stack> {
+  #[inline(always)]
+  fn base(&self) -> *mut u8 {
+    self.stack.base()
+  }
+
+  #[inline(always)]
+  fn limit(&self) -> *mut u8 {
+    self.stack.limit()
+  }
+}
+
+unsafe impl<Stack: stack::GuardedStack> stack::GuardedStack for PaintedStack<Stack> {
+  #[inline(always)]
+  fn guard_size(&self) -> usize {
+    self.stack.guard_size()
+  }
+}
+
+impl<'a> From<SliceStack<'a>> for PaintedStack<SliceStack<'a>> {
+  /// Fills the memory of `stack` with [`STACK_PAINT`](constant.STACK_PAINT.html).
+  fn from(stack: SliceStack<'a>) -> PaintedStack<SliceStack<'a>> {
+    // The stack lies within the slice, which is borrowed mutably.
+    unsafe { PaintedStack::new(stack) }
+  }
+}
+
+#[cfg(feature = "alloc")]
+impl From<OwnedStack> for PaintedStack<OwnedStack> {
+  /// Fills the memory of `stack` with [`STACK_PAINT`](constant.STACK_PAINT.html).
+  fn from(stack: OwnedStack) -> PaintedStack<OwnedStack> {
+    // The stack lies within its allocation, which it owns.
+    unsafe { PaintedStack::new(stack) }
+  }
+}
+
+#[cfg(unix)]
+impl From<OsStack> for PaintedStack<OsStack> {
+  /// Fills the memory of `stack` with [`STACK_PAINT`](constant.STACK_PAINT.html).
+  fn from(stack: OsStack) -> PaintedStack<OsStack> {
+    // The stack lies within its mapping, which it owns; the guard pages are below its limit.
+    unsafe { PaintedStack::new(stack) }
+  }
+}
+
+/// Runs `f` on a painted [`OsStack`](struct.OsStack.html) of at least `size` bytes,
+/// and returns its return value along with the number of bytes of the stack it has used,
+/// as reported by [`PaintedStack::high_water`](struct.PaintedStack.html#method.high_water).
+/// This includes a few hundred bytes taken by libfringe itself.
+///
+/// If the stack cannot be allocated, or `f` panics, panics.
+///
+/// # Example
+///
+/// ```
+/// let (sum, usage) = fringe::measure_stack_usage(0, || {
+///   let buffer = [1u8; 4096];
+///   buffer.iter().map(|&x| x as u32).sum::<u32>()
+/// });
+/// println!("{} {}", sum, usage); // prints 4096 and something above 4096
+/// ```
+#[cfg(unix)]
+pub fn measure_stack_usage<F, R>(size: usize, f: F) -> (R, usize)
+    where F: FnOnce() -> R {
+  let stack = ::OsStack::new(size).expect("cannot allocate stack");
+  ::scope(|s| {
+    let mut generator = s.generator(PaintedStack::from(stack), |_: &mut Yielder<(), ()>, ()| f());
+    match generator.resume_state(()) {
+      GeneratorState::Complete(value) => (value, generator.unwrap().generator_high_water()),
+      GeneratorState::Yielded(()) => unreachable!()
+    }
+  })
+}
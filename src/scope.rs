@@ -0,0 +1,170 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+use core::marker::PhantomData;
+
+#[cfg(feature = "std")]
+use std::any::Any;
+#[cfg(feature = "std")]
+use std::boxed::Box;
+
+use stack::{self, StackError};
+use generator::{Generator, GeneratorState, State, Yielder, Lend};
+#[cfg(feature = "std")]
+use generator::StackOverflow;
+
+/// Creates a scope in which generators can borrow data from the stack frame of the caller.
+///
+/// The function `f` is passed a [`Scope`](struct.Scope.html) that creates generators
+/// whose generator functions may borrow anything that outlives the call to `scope`.
+/// Such a generator cannot escape `f`; by the time `scope` returns, every generator
+/// created in it has either finished or been dropped, which unwinds its stack.
+///
+/// Forgetting a scoped generator (e.g. with `mem::forget`) is safe: it is never resumed
+/// again, so nothing it borrows is accessed after the scope ends, but its stack and
+/// everything the generator function holds are leaked.
+///
+/// # Example
+///
+/// ```
+/// use fringe::OsStack;
+///
+/// let words = vec!["foo".to_string(), "bar".to_string()];
+/// fringe::scope(|s| {
+///   let stack = OsStack::new(0).unwrap();
+///   let mut lengths = s.generator(stack, |yielder, ()| {
+///     for word in &words { yielder.suspend(word.len()) }
+///   });
+///   println!("{:?}", lengths.next()); // prints Some(3)
+/// });
+/// println!("{:?}", words); // prints ["foo", "bar"]
+/// ```
+pub fn scope<'env, F, T>(f: F) -> T
+    where F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T {
+  let scope = Scope { scope: PhantomData, env: PhantomData };
+  f(&scope)
+}
+
+/// A scope for creating generators that borrow data from outside of it;
+/// see [`scope`](fn.scope.html).
+#[derive(Debug)]
+pub struct Scope<'scope, 'env: 'scope> {
+  scope: PhantomData<&'scope mut &'scope ()>,
+  env:   PhantomData<&'env mut &'env ()>
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+  /// Creates a new generator within the scope; see
+  /// [`Generator::new`](generator/struct.Generator.html#method.new).
+  pub fn generator<Input, Output, Stack, Return, F>(&'scope self, stack: Stack, f: F)
+                                                   -> ScopedGenerator<'scope, Input, Output, Stack, Return>
+      where Stack: stack::Stack + stack::GuardedStack,
+            F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return + 'scope {
+    match self.try_generator(stack, f) {
+      Ok(generator) => generator,
+      Err(err) => panic!("cannot create generator: {}", err)
+    }
+  }
+
+  /// Same as `generator`, but returns an error instead of panicking if `stack` is unsuitable;
+  /// see [`Generator::try_new`](generator/struct.Generator.html#method.try_new).
+  pub fn try_generator<Input, Output, Stack, Return, F>(&'scope self, stack: Stack, f: F)
+      -> Result<ScopedGenerator<'scope, Input, Output, Stack, Return>, StackError>
+      where Stack: stack::Stack + stack::GuardedStack,
+            F: FnOnce(&mut Yielder<Input, Output>, Input) -> Return + 'scope {
+    // The generator cannot outlive 'scope, and it unwinds its stack when dropped.
+    let generator = unsafe { Generator::try_new_unbounded(stack, f)? };
+    Ok(ScopedGenerator { generator, scope: PhantomData })
+  }
+}
+
+/// ScopedGenerator is a generator whose generator function borrows data from outside
+/// of a [`scope`](fn.scope.html). It behaves exactly like a
+/// [`Generator`](generator/struct.Generator.html), but cannot outlive the scope.
+#[derive(Debug)]
+pub struct ScopedGenerator<'scope, Input, Output, Stack: stack::Stack, Return = ()> {
+  generator: Generator<Input, Output, Stack, Return>,
+  scope:     PhantomData<&'scope ()>
+}
+
+impl<'scope, Input, Output, Stack, Return> ScopedGenerator<'scope, Input, Output, Stack, Return>
+    where Stack: stack::Stack {
+  /// Same as [`Generator::resume`](generator/struct.Generator.html#method.resume).
+  #[inline]
+  pub fn resume(&mut self, input: Input) -> Option<Output> {
+    self.generator.resume(input)
+  }
+
+  /// Same as [`Generator::resume_state`](generator/struct.Generator.html#method.resume_state).
+  #[inline]
+  pub fn resume_state(&mut self, input: Input) -> GeneratorState<Output, Return> {
+    self.generator.resume_state(input)
+  }
+
+  /// Same as [`Generator::throw`](generator/struct.Generator.html#method.throw).
+  #[inline]
+  pub fn throw<E: 'static>(&mut self, err: E) -> Option<Output> {
+    self.generator.throw(err)
+  }
+
+  /// Same as [`Generator::throw_state`](generator/struct.Generator.html#method.throw_state).
+  #[inline]
+  pub fn throw_state<E: 'static>(&mut self, err: E) -> GeneratorState<Output, Return> {
+    self.generator.throw_state(err)
+  }
+
+  /// Same as [`Generator::resume_catching`](generator/struct.Generator.html#method.resume_catching).
+  #[cfg(feature = "std")]
+  #[inline]
+  pub fn resume_catching(&mut self, input: Input)
+                        -> Result<GeneratorState<Output, Return>, Box<dyn Any + Send>> {
+    self.generator.resume_catching(input)
+  }
+
+  /// Same as [`Generator::try_resume`](generator/struct.Generator.html#method.try_resume).
+  #[cfg(feature = "std")]
+  #[inline]
+  pub fn try_resume(&mut self, input: Input) -> Result<Option<Output>, StackOverflow> {
+    self.generator.try_resume(input)
+  }
+
+  /// Same as [`Generator::cancel`](generator/struct.Generator.html#method.cancel).
+  #[cfg(feature = "std")]
+  pub fn cancel(&mut self) {
+    self.generator.cancel()
+  }
+
+  /// Returns the state of the generator.
+  #[inline]
+  pub fn state(&self) -> State { self.generator.state() }
+
+  /// Same as [`Generator::unwrap`](generator/struct.Generator.html#method.unwrap).
+  pub fn unwrap(self) -> Stack {
+    self.generator.unwrap()
+  }
+}
+
+impl<'scope, Input, T: ?Sized, Stack, Return> ScopedGenerator<'scope, Input, Lend<T>, Stack, Return>
+    where Stack: stack::Stack {
+  /// Same as [`Generator::resume_ref`](generator/struct.Generator.html#method.resume_ref).
+  #[inline]
+  pub fn resume_ref(&mut self, input: Input) -> Option<&T> {
+    self.generator.resume_ref(input)
+  }
+
+  /// Same as [`Generator::resume_state_ref`](generator/struct.Generator.html#method.resume_state_ref).
+  #[inline]
+  pub fn resume_state_ref(&mut self, input: Input) -> GeneratorState<&T, Return> {
+    self.generator.resume_state_ref(input)
+  }
+}
+
+impl<'scope, Output, Stack, Return> Iterator for ScopedGenerator<'scope, (), Output, Stack, Return>
+    where Stack: stack::Stack {
+  type Item = Output;
+
+  fn next(&mut self) -> Option<Self::Item> { self.resume(()) }
+}
@@ -5,6 +5,7 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 //! Traits for stacks.
+use core::fmt;
 
 /// A trait for objects that hold ownership of a stack.
 ///
@@ -27,11 +28,86 @@ pub trait Stack {
   fn limit(&self) -> *mut u8;
 }
 
-/// A marker trait for `Stack` objects with a guard page.
+/// A trait for `Stack` objects with a guard page.
+///
+/// # Safety
 ///
 /// To preserve memory safety, an implementation of this trait must fulfill
 /// the following contract, in addition to the [contract](trait.Stack.html) of `Stack`:
 ///
-///   * Any access of data at addresses `limit()` to `limit().offset(4096)` must
+///   * Any access of data at addresses `limit().offset(-guard_size())` to `limit()` must
 ///     abnormally terminate, at least, the thread that performs the access.
-pub unsafe trait GuardedStack {}
+pub unsafe trait GuardedStack {
+  /// Returns the size of the guard below the limit of the stack, in bytes.
+  fn guard_size(&self) -> usize { 4096 }
+}
+
+/// The reason a stack cannot be used to run a generator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackError {
+  /// The stack is smaller than [`MIN_STACK_SIZE`][min] bytes.
+  ///
+  /// [min]: constant.MIN_STACK_SIZE.html
+  TooSmall {
+    /// The size of the stack, i.e. `base() - limit()`.
+    size: usize,
+    /// The minimum size of a stack.
+    min:  usize
+  },
+  /// The base address of the stack is not aligned to
+  /// a [`STACK_ALIGNMENT`][align]-byte boundary.
+  ///
+  /// [align]: constant.STACK_ALIGNMENT.html
+  Misaligned,
+  /// The guard of the stack is smaller than [`MIN_GUARD_SIZE`][min] bytes.
+  ///
+  /// [min]: constant.MIN_GUARD_SIZE.html
+  GuardTooSmall {
+    /// The size of the guard, i.e. `guard_size()`.
+    size: usize,
+    /// The minimum size of a guard.
+    min:  usize
+  }
+}
+
+impl StackError {
+  /// Checks that `stack` is large enough and aligned properly to run a generator.
+  pub(crate) fn check(stack: &dyn Stack) -> Result<(), StackError> {
+    let base = stack.base() as usize;
+    let size = base.saturating_sub(stack.limit() as usize);
+    if !base.is_multiple_of(::STACK_ALIGNMENT) {
+      Err(StackError::Misaligned)
+    } else if size < ::MIN_STACK_SIZE {
+      Err(StackError::TooSmall { size, min: ::MIN_STACK_SIZE })
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Checks that the guard of `stack` is large enough to catch every overflow
+  /// on the target platform.
+  pub(crate) fn check_guard<Stack: GuardedStack + ?Sized>(stack: &Stack) -> Result<(), StackError> {
+    let size = stack.guard_size();
+    if size < ::MIN_GUARD_SIZE {
+      Err(StackError::GuardTooSmall { size, min: ::MIN_GUARD_SIZE })
+    } else {
+      Ok(())
+    }
+  }
+}
+
+impl fmt::Display for StackError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      StackError::TooSmall { size, min } =>
+        write!(f, "stack of {} bytes is smaller than the minimum of {} bytes", size, min),
+      StackError::Misaligned =>
+        write!(f, "stack base is not aligned to {} bytes", ::STACK_ALIGNMENT),
+      StackError::GuardTooSmall { size, min } =>
+        write!(f, "stack guard of {} bytes is smaller than the minimum of {} bytes", size, min)
+    }
+  }
+}
+
+#[cfg(feature = "std")]
+impl ::std::error::Error for StackError {}
@@ -1,7 +1,22 @@
+use core::mem::MaybeUninit;
+
 use arch;
 use stack::Stack;
 
+/// Number of words passed in registers while swapping context.
+pub const NUM_REGS: usize = arch::NUM_REGS;
+
+/// The words passed in registers while swapping context. A value passed in them
+/// may contain padding, so they are not necessarily initialized.
+pub type Regs = [MaybeUninit<usize>; NUM_REGS];
+
+/// The type of the function that is called when a context is first switched to.
+/// It receives the argument of that first switch and the stack pointer of the
+/// context that performed it, and must never return.
+pub type Trampoline = unsafe extern "C" fn(usize, StackPointer) -> !;
+
 #[derive(Debug, Clone, Copy)]
+#[repr(transparent)]
 /// The bare-minimum context. It's quite unsafe
 pub struct StackPointer(pub *mut usize);
 
@@ -12,29 +27,40 @@ impl StackPointer {
     *self.0 = val
   }
 
-  pub unsafe fn init(
-    new_stack: &Stack,
-    fun: unsafe extern "C" fn(usize, StackPointer) -> !)
-    -> StackPointer
-  {
+  pub unsafe fn init(new_stack: &dyn Stack, fun: Trampoline) -> StackPointer {
     let mut sp = StackPointer(new_stack.base() as _);
     arch::init(&mut sp, fun);
     sp
   }
 
   #[inline(always)]
+  #[cfg_attr(not(feature = "std"), allow(dead_code))]
   pub unsafe fn swap(arg: usize, new_sp: StackPointer,
-                     new_stack: Option<&Stack>) -> (usize, StackPointer)
+                     new_stack: Option<&dyn Stack>) -> (usize, StackPointer)
+  {
+    let mut args = [MaybeUninit::uninit(); NUM_REGS];
+    args[0] = MaybeUninit::new(arg);
+    let (rets, sp) = arch::swap(args, new_sp, new_stack);
+    (rets[0].assume_init(), sp)
+  }
+
+  /// Same as `swap`, but passes `NUM_REGS` words. The first switch to a new
+  /// context passes only the first one.
+  #[inline(always)]
+  pub unsafe fn swap_args(args: Regs, new_sp: StackPointer,
+                          new_stack: Option<&dyn Stack>) -> (Regs, StackPointer)
   {
-    arch::swap(arg, new_sp, new_stack)
+    arch::swap(args, new_sp, new_stack)
   }
 }
 
 
 #[cfg(test)]
 mod tests {
-  extern crate test;
-  extern crate simd;
+  use std::any::Any;
+  use std::boxed::Box;
+  use std::hint::black_box;
+  use std::panic::{self, AssertUnwindSafe};
 
   use super::StackPointer;
   use ::OsStack;
@@ -62,16 +88,23 @@ mod tests {
 
   #[test]
   fn context_simd() {
+    #[repr(align(16))]
+    #[derive(Clone, Copy)]
+    struct Aligned([i32; 4]);
+
+    fn check_alignment(arg: usize) {
+      // Locals with 16-byte alignment are placed relative to the stack pointer
+      // without realigning it, so this will fail if the stack is not aligned properly.
+      let x = black_box(Aligned([arg as i32; 4]));
+      assert_eq!(black_box(&x as *const Aligned as usize) % 16, 0);
+      println!("aligned result: {:?}", x.0);
+    }
+
     unsafe extern "C" fn permuter(arg: usize, stack_ptr: StackPointer) -> ! {
-      // This will crash if the stack is not aligned properly.
-      let x = simd::i32x4::splat(arg as i32);
-      let y = x * x;
-      println!("simd result: {:?}", y);
-      let (_, stack_ptr) = StackPointer::swap(0, stack_ptr, None);
+      check_alignment(arg);
+      let (arg, stack_ptr) = StackPointer::swap(0, stack_ptr, None);
       // And try again after a context switch.
-      let x = simd::i32x4::splat(arg as i32);
-      let y = x * x;
-      println!("simd result: {:?}", y);
+      check_alignment(arg);
       StackPointer::swap(0, stack_ptr, None);
       panic!("i should be dead");
     }
@@ -85,15 +118,25 @@ mod tests {
     }
   }
 
-  unsafe extern "C" fn do_panic(arg: usize, stack_ptr: StackPointer) -> ! {
-    match arg {
-      0 => panic!("arg=0"),
-      1 => {
-        StackPointer::swap(0, stack_ptr, None);
-        panic!("arg=1");
+  // Unwinding must not cross a context boundary, so the panic is caught
+  // at the bottom of the context and handed over to the resumer.
+  unsafe extern "C" fn do_panic(arg: usize, mut stack_ptr: StackPointer) -> ! {
+    let payload = panic::catch_unwind(AssertUnwindSafe(|| {
+      match arg {
+        0 => panic!("arg=0"),
+        1 => {
+          stack_ptr = StackPointer::swap(0, stack_ptr, None).1;
+          panic!("arg=1");
+        }
+        _ => unreachable!()
       }
-      _ => unreachable!()
-    }
+    })).unwrap_err();
+    StackPointer::swap(Box::into_raw(Box::new(payload)) as usize, stack_ptr, None);
+    unreachable!()
+  }
+
+  unsafe fn resume_panic(data: usize) -> ! {
+    panic::resume_unwind(*Box::from_raw(data as *mut Box<dyn Any + Send>))
   }
 
   #[test]
@@ -103,7 +146,8 @@ mod tests {
       let stack = OsStack::new(4 << 20).unwrap();
       let stack_ptr = StackPointer::init(&stack, do_panic);
 
-      StackPointer::swap(0, stack_ptr, Some(&stack));
+      let (data, _) = StackPointer::swap(0, stack_ptr, Some(&stack));
+      resume_panic(data);
     }
   }
 
@@ -115,29 +159,8 @@ mod tests {
       let stack_ptr = StackPointer::init(&stack, do_panic);
 
       let (_, stack_ptr) = StackPointer::swap(1, stack_ptr, Some(&stack));
-      StackPointer::swap(0, stack_ptr, Some(&stack));
-    }
-  }
-
-  #[bench]
-  fn swap(b: &mut test::Bencher) {
-    unsafe extern "C" fn loopback(mut arg: usize, mut stack_ptr: StackPointer) -> ! {
-      // This deliberately does not ignore arg, to measure the time it takes
-      // to move the return value between registers.
-      loop {
-        let data = StackPointer::swap(arg, stack_ptr, None);
-        arg = data.0;
-        stack_ptr = data.1;
-      }
-    }
-
-    unsafe {
-      let stack = OsStack::new(4 << 20).unwrap();
-      let mut stack_ptr = StackPointer::init(&stack, loopback);
-
-      b.iter(|| for _ in 0..10 {
-        stack_ptr = StackPointer::swap(0, stack_ptr, Some(&stack)).1;
-      });
+      let (data, _) = StackPointer::swap(0, stack_ptr, Some(&stack));
+      resume_panic(data);
     }
   }
 }
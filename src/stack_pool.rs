@@ -0,0 +1,363 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+extern crate std;
+
+use core::cell::RefCell;
+use self::std::io::Error as IoError;
+use self::std::rc::{self, Rc};
+use self::std::sync::{self, Arc, Mutex, PoisonError};
+use self::std::vec::Vec;
+
+use stack::{Stack, GuardedStack};
+use OsStack;
+
+/// Statistics of a stack pool.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StackPoolStats {
+  /// The number of stacks allocated from the operating system.
+  pub allocated: usize,
+  /// The number of stacks handed out from the pool, without allocating them.
+  pub reused:    usize,
+  /// The number of stacks that went back to the pool when they were dropped.
+  pub returned:  usize,
+  /// The number of stacks that were unmapped when they were dropped, because the pool
+  /// was full or they were larger than every size class.
+  pub released:  usize,
+  /// The number of stacks in the pool.
+  pub idle:      usize
+}
+
+/// StackPoolBuilder configures a [`StackPool`](struct.StackPool.html) or
+/// a [`SyncStackPool`](struct.SyncStackPool.html) before creating it.
+#[derive(Debug, Clone)]
+pub struct StackPoolBuilder {
+  classes:     Vec<usize>,
+  cap:         usize,
+  guard_pages: Option<usize>,
+  lazy:        bool,
+  decommit:    bool
+}
+
+impl StackPoolBuilder {
+  fn new() -> StackPoolBuilder {
+    let classes = std::vec![::RECOMMENDED_STACK_SIZE];
+    StackPoolBuilder { classes, cap: 64, guard_pages: None, lazy: false, decommit: false }
+  }
+
+  /// Sets the sizes of the stacks the pool allocates. A request for a stack is served
+  /// with one of the smallest size that fits it; a request for a stack larger than every
+  /// size class is served with a stack that is unmapped rather than pooled once it is dropped.
+  /// The default is a single size class of
+  /// [`RECOMMENDED_STACK_SIZE`](constant.RECOMMENDED_STACK_SIZE.html) bytes.
+  pub fn size_classes(self, sizes: &[usize]) -> StackPoolBuilder {
+    let mut classes = sizes.to_vec();
+    classes.sort_unstable();
+    classes.dedup();
+    StackPoolBuilder { classes, ..self }
+  }
+
+  /// Sets the maximum number of stacks the pool holds; stacks dropped while it is full
+  /// are unmapped. The default is 64.
+  pub fn cap(self, cap: usize) -> StackPoolBuilder {
+    StackPoolBuilder { cap, ..self }
+  }
+
+  /// Sets the number of guard pages of the stacks; see
+  /// [`OsStackBuilder::guard_pages`](struct.OsStackBuilder.html#method.guard_pages).
+  pub fn guard_pages(self, guard_pages: usize) -> StackPoolBuilder {
+    StackPoolBuilder { guard_pages: Some(guard_pages), ..self }
+  }
+
+  /// Sets whether the stacks only reserve address space; see
+  /// [`OsStackBuilder::lazy`](struct.OsStackBuilder.html#method.lazy). The default is `false`.
+  pub fn lazy(self, lazy: bool) -> StackPoolBuilder {
+    StackPoolBuilder { lazy, ..self }
+  }
+
+  /// Sets whether the pages of a stack are returned to the operating system when it goes
+  /// back to the pool, using [`OsStack::decommit`](struct.OsStack.html#method.decommit).
+  /// This keeps idle stacks from taking up memory, at the cost of a system call on every drop
+  /// and faulting the pages in again once the stack is reused. The default is `false`.
+  pub fn decommit(self, decommit: bool) -> StackPoolBuilder {
+    StackPoolBuilder { decommit, ..self }
+  }
+
+  /// Creates a pool that can only be used on the current thread.
+  pub fn build(self) -> StackPool {
+    StackPool { inner: Rc::new(Inner::new(self)) }
+  }
+
+  /// Creates a pool that can be shared between threads.
+  pub fn build_sync(self) -> SyncStackPool {
+    SyncStackPool { inner: Arc::new(Inner::new(self)) }
+  }
+}
+
+/// The stacks in a pool, and its statistics.
+#[derive(Debug)]
+struct Shelves {
+  /// The stacks of every size class.
+  free:    Vec<Vec<OsStack>>,
+  /// The number of stacks being decommitted, for which there is room reserved in the pool.
+  pending: usize,
+  stats:   StackPoolStats
+}
+
+/// Exclusive access to the shelves of a pool.
+trait Lock {
+  fn with<R, F: FnOnce(&mut Shelves) -> R>(&self, f: F) -> R;
+}
+
+impl Lock for RefCell<Shelves> {
+  fn with<R, F: FnOnce(&mut Shelves) -> R>(&self, f: F) -> R {
+    f(&mut self.borrow_mut())
+  }
+}
+
+impl Lock for Mutex<Shelves> {
+  fn with<R, F: FnOnce(&mut Shelves) -> R>(&self, f: F) -> R {
+    f(&mut self.lock().unwrap_or_else(PoisonError::into_inner))
+  }
+}
+
+#[derive(Debug)]
+struct Inner<L> {
+  config:  StackPoolBuilder,
+  shelves: L
+}
+
+impl<L: Lock + From<Shelves>> Inner<L> {
+  fn new(config: StackPoolBuilder) -> Inner<L> {
+    let free = config.classes.iter().map(|_| Vec::new()).collect();
+    Inner { config, shelves: L::from(Shelves { free, pending: 0, stats: StackPoolStats::default() }) }
+  }
+}
+
+impl<L: Lock> Inner<L> {
+  /// Returns a stack of at least `size` bytes, and its size class, if any.
+  fn get(&self, size: usize) -> Result<(OsStack, Option<usize>), IoError> {
+    let size = if size == 0 { ::RECOMMENDED_STACK_SIZE } else { size };
+    let class = self.config.classes.iter().position(|&class| class >= size);
+    if let Some(class) = class {
+      let stack = self.shelves.with(|shelves| {
+        let stack = shelves.free[class].pop();
+        if stack.is_some() {
+          shelves.stats.reused += 1;
+          shelves.stats.idle -= 1;
+        }
+        stack
+      });
+      if let Some(stack) = stack { return Ok((stack, Some(class))) }
+    }
+
+    // Allocate the stack without holding the lock.
+    let size = class.map_or(size, |class| self.config.classes[class]);
+    let mut builder = OsStack::builder().size(size).lazy(self.config.lazy);
+    if let Some(guard_pages) = self.config.guard_pages {
+      builder = builder.guard_pages(guard_pages)
+    }
+    let stack = builder.build()?;
+    self.shelves.with(|shelves| shelves.stats.allocated += 1);
+    Ok((stack, class))
+  }
+
+  /// Takes back a stack of size class `class`, if any.
+  fn put(&self, mut stack: OsStack, class: Option<usize>) {
+    // Reserve room for the stack first, so that a stack that is going to be unmapped
+    // is not decommitted for nothing.
+    let reserved = self.shelves.with(|shelves| {
+      let reserved = class.is_some() && shelves.stats.idle + shelves.pending < self.config.cap;
+      if reserved { shelves.pending += 1 } else { shelves.stats.released += 1 }
+      reserved
+    });
+    let class = match class {
+      Some(class) if reserved => class,
+      // Unmap the stack without holding the lock.
+      _ => return
+    };
+    // A stack that cannot be decommitted would still be fine to reuse, but the pool
+    // would not live up to what it promises; unmap it instead.
+    let kept = !self.config.decommit || stack.decommit().is_ok();
+    let rejected = self.shelves.with(|shelves| {
+      shelves.pending -= 1;
+      if kept {
+        shelves.free[class].push(stack);
+        shelves.stats.returned += 1;
+        shelves.stats.idle += 1;
+        None
+      } else {
+        shelves.stats.released += 1;
+        Some(stack)
+      }
+    });
+    // Unmap the stack without holding the lock.
+    drop(rejected)
+  }
+
+  fn stats(&self) -> StackPoolStats {
+    self.shelves.with(|shelves| shelves.stats)
+  }
+}
+
+/// StackPool hands out stacks allocated with [`OsStack`](struct.OsStack.html), which go back
+/// to it when they are dropped.
+///
+/// Allocating an `OsStack` takes an `mmap` and an `mprotect`, and dropping it a `munmap`.
+/// A pool keeps the stacks that are dropped, up to a cap, and hands them out again, so that
+/// short-lived generators take no system calls at all. Every stack belongs to a size class,
+/// and is only reused for requests that fit in it.
+///
+/// A StackPool can only be used on the thread that has created it; a pool that can be shared
+/// between threads is a [`SyncStackPool`](struct.SyncStackPool.html).
+///
+/// # Example
+///
+/// ```
+/// use fringe::{StackPool, Generator};
+///
+/// let pool = StackPool::new();
+/// for i in 0..3 {
+///   let mut generator = Generator::new(pool.get(0).unwrap(), move |yielder, ()| {
+///     yielder.suspend(i)
+///   });
+///   println!("{:?}", generator.resume(())); // prints Some(0), Some(1), Some(2)
+/// }
+/// println!("{}", pool.stats().allocated); // prints 1
+/// ```
+#[derive(Debug, Clone)]
+pub struct StackPool {
+  inner: Rc<Inner<RefCell<Shelves>>>
+}
+
+impl StackPool {
+  /// Creates a pool with the default configuration; see
+  /// [`StackPoolBuilder`](struct.StackPoolBuilder.html).
+  pub fn new() -> StackPool {
+    StackPool::builder().build()
+  }
+
+  /// Returns a builder for a pool with a custom configuration.
+  pub fn builder() -> StackPoolBuilder {
+    StackPoolBuilder::new()
+  }
+
+  /// Returns a stack with at least `size` accessible bytes, from the pool if it holds one
+  /// of the right size class, and otherwise newly allocated; `get(0)` returns a stack of
+  /// [`RECOMMENDED_STACK_SIZE`](constant.RECOMMENDED_STACK_SIZE.html) bytes.
+  pub fn get(&self, size: usize) -> Result<PooledStack, IoError> {
+    let (stack, class) = self.inner.get(size)?;
+    Ok(PooledStack { stack: Some(stack), class, pool: Rc::downgrade(&self.inner) })
+  }
+
+  /// Returns the statistics of the pool.
+  pub fn stats(&self) -> StackPoolStats {
+    self.inner.stats()
+  }
+}
+
+impl Default for StackPool {
+  fn default() -> StackPool {
+    StackPool::new()
+  }
+}
+
+/// SyncStackPool is the same as [`StackPool`](struct.StackPool.html), but can be shared
+/// between threads, and the stacks it hands out can be sent to other threads.
+#[derive(Debug, Clone)]
+pub struct SyncStackPool {
+  inner: Arc<Inner<Mutex<Shelves>>>
+}
+
+impl SyncStackPool {
+  /// Creates a pool with the default configuration; see
+  /// [`StackPoolBuilder`](struct.StackPoolBuilder.html).
+  pub fn new() -> SyncStackPool {
+    SyncStackPool::builder().build_sync()
+  }
+
+  /// Returns a builder for a pool with a custom configuration.
+  pub fn builder() -> StackPoolBuilder {
+    StackPoolBuilder::new()
+  }
+
+  /// Same as [`StackPool::get`](struct.StackPool.html#method.get).
+  pub fn get(&self, size: usize) -> Result<SyncPooledStack, IoError> {
+    let (stack, class) = self.inner.get(size)?;
+    Ok(SyncPooledStack { stack: Some(stack), class, pool: Arc::downgrade(&self.inner) })
+  }
+
+  /// Returns the statistics of the pool.
+  pub fn stats(&self) -> StackPoolStats {
+    self.inner.stats()
+  }
+}
+
+impl Default for SyncStackPool {
+  fn default() -> SyncStackPool {
+    SyncStackPool::new()
+  }
+}
+
+/// PooledStack holds a stack handed out by a [`StackPool`](struct.StackPool.html),
+/// which it goes back to when it is dropped. If the pool is gone by then, it is unmapped.
+#[derive(Debug)]
+pub struct PooledStack {
+  stack: Option<OsStack>,
+  class: Option<usize>,
+  pool:  rc::Weak<Inner<RefCell<Shelves>>>
+}
+
+/// SyncPooledStack holds a stack handed out by a [`SyncStackPool`](struct.SyncStackPool.html),
+/// which it goes back to when it is dropped. If the pool is gone by then, it is unmapped.
+#[derive(Debug)]
+pub struct SyncPooledStack {
+  stack: Option<OsStack>,
+  class: Option<usize>,
+  pool:  sync::Weak<Inner<Mutex<Shelves>>>
+}
+
+macro_rules! pooled_stack {
+  ($name:ident) => {
+    impl $name {
+      #[inline(always)]
+      fn stack(&self) -> &OsStack {
+        // Only ever taken on drop.
+        self.stack.as_ref().unwrap()
+      }
+    }
+
+    impl Stack for $name {
+      #[inline(always)]
+      fn base(&self) -> *mut u8 {
+        self.stack().base()
+      }
+
+      #[inline(always)]
+      fn limit(&self) -> *mut u8 {
+        self.stack().limit()
+      }
+    }
+
+    unsafe impl GuardedStack for $name {
+      #[inline(always)]
+      fn guard_size(&self) -> usize {
+        self.stack().guard_size()
+      }
+    }
+
+    impl Drop for $name {
+      fn drop(&mut self) {
+        if let (Some(stack), Some(pool)) = (self.stack.take(), self.pool.upgrade()) {
+          pool.put(stack, self.class)
+        }
+      }
+    }
+  }
+}
+
+pooled_stack!(PooledStack);
+pooled_stack!(SyncPooledStack);
@@ -0,0 +1,71 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+extern crate fringe;
+
+use fringe::OsStack;
+use fringe::context::Context;
+
+#[test]
+fn ping_pong() {
+  let stack = OsStack::new(0).unwrap();
+  unsafe {
+    let mut context = Context::new(&stack, |mut caller: Context, mut value: usize| {
+      while value != 0 { (caller, value) = caller.switch(value + 1) }
+      (caller, 100usize)
+    });
+    for i in 1..10 {
+      let (next, value) = context.switch(i);
+      assert_eq!(value, i + 1);
+      context = next;
+    }
+    let (_, value) = context.switch(0);
+    assert_eq!(value, 100);
+  }
+}
+
+#[test]
+fn typed_payloads() {
+  let stack = OsStack::new(0).unwrap();
+  unsafe {
+    let context = Context::new(&stack, |caller: Context, (a, b): (u64, u64)| {
+      let (caller, text): (Context, String) = caller.switch_with(a * b);
+      (caller, text + "!")
+    });
+    let (context, product): (Context, u64) = context.switch_with((6u64, 7u64));
+    assert_eq!(product, 42);
+    let (_, text): (Context, String) = context.switch_with("done".to_string());
+    assert_eq!(text, "done!");
+  }
+}
+
+#[test]
+fn symmetric() {
+  // Two contexts hand control directly to each other, never returning to main
+  // until both are done.
+  let stack_a = OsStack::new(0).unwrap();
+  let stack_b = OsStack::new(0).unwrap();
+  unsafe {
+    let b = Context::new(&stack_b, |a: Context, (main, mut log): (Context, Vec<&'static str>)| {
+      log.push("b");
+      let (a, (main, mut log)): (Context, (Context, Vec<&'static str>)) =
+        a.switch_with((main, log));
+      log.push("b again");
+      (a, (main, log))
+    });
+    let a = Context::new(&stack_a, move |main: Context, ()| {
+      let (b, (main, mut log)): (Context, (Context, Vec<&'static str>)) =
+        b.switch_with((main, vec!["a"]));
+      log.push("a again");
+      let (_, (main, mut log)): (Context, (Context, Vec<&'static str>)) =
+        b.switch_with((main, log));
+      log.push("a last");
+      (main, log)
+    });
+    let (_, log): (Context, Vec<&'static str>) = a.switch_with(());
+    assert_eq!(log, vec!["a", "b", "a again", "b again", "a last"]);
+  }
+}
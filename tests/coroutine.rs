@@ -0,0 +1,170 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+extern crate fringe;
+
+use std::cell::{Cell, OnceCell};
+use std::panic::{self, AssertUnwindSafe};
+use std::rc::Rc;
+
+use fringe::{OsStack, OwnedStack, Coroutine, StackError};
+use fringe::coroutine::State;
+
+struct SetOnDrop(Rc<Cell<bool>>);
+
+impl Drop for SetOnDrop {
+  fn drop(&mut self) {
+    self.0.set(true)
+  }
+}
+
+#[test]
+fn pipeline() {
+  let sink = Rc::new(Coroutine::new(OsStack::new(0).unwrap(), |current, mut value: Vec<&str>| {
+    loop {
+      value.push("sink");
+      value = current.suspend(value)
+    }
+  }));
+  let middle = Rc::new(Coroutine::new(OsStack::new(0).unwrap(), move |current, mut value: Vec<&str>| {
+    loop {
+      value.push("middle");
+      value = current.transfer(&sink, value)
+    }
+  }));
+  let source = Coroutine::new(OsStack::new(0).unwrap(), move |current, mut value: Vec<&str>| {
+    loop {
+      value.push("source");
+      value = current.transfer(&middle, value)
+    }
+  });
+  for _ in 0..3 {
+    assert_eq!(source.resume(vec![]), vec!["source", "middle", "sink"]);
+  }
+}
+
+#[test]
+fn ping_pong() {
+  let ping: Rc<OnceCell<Coroutine<u32, OsStack>>> = Rc::new(OnceCell::new());
+  let pong = {
+    let ping = ping.clone();
+    Rc::new(Coroutine::new(OsStack::new(0).unwrap(), move |current, mut value| {
+      while value < 10 { value = current.transfer(ping.get().unwrap(), value + 1) }
+      value
+    }))
+  };
+  let _ = ping.set(Coroutine::new(OsStack::new(0).unwrap(), move |current, mut value| {
+    loop { value = current.transfer(&pong, value + 1) }
+  }));
+  let ping = ping.get().unwrap();
+  assert_eq!(ping.resume(0), 11);
+  assert_eq!(ping.state(), State::Suspended);
+}
+
+#[test]
+fn returned() {
+  let coroutine = Coroutine::new(OsStack::new(0).unwrap(), |current, value| {
+    current.suspend(value + 1) * 2
+  });
+  assert_eq!(coroutine.resume(1), 2);
+  assert_eq!(coroutine.state(), State::Suspended);
+  assert_eq!(coroutine.resume(5), 10);
+  assert_eq!(coroutine.state(), State::Finished);
+  coroutine.unwrap();
+}
+
+#[test]
+fn nested_resume() {
+  let inner = Coroutine::new(OsStack::new(0).unwrap(), |current, mut value| {
+    loop { value = current.suspend(value * 10) }
+  });
+  let outer = Coroutine::new(OsStack::new(0).unwrap(), move |current, mut value| {
+    loop { value = current.suspend(inner.resume(value) + 1) }
+  });
+  assert_eq!(outer.resume(1), 11);
+  assert_eq!(outer.resume(2), 21);
+}
+
+#[test]
+fn transfer_to_self() {
+  let this: Rc<OnceCell<Coroutine<(), OsStack>>> = Rc::new(OnceCell::new());
+  let _ = this.set({
+    let this = this.clone();
+    Coroutine::new(OsStack::new(0).unwrap(), move |current, ()| {
+      current.transfer(this.get().unwrap(), ())
+    })
+  });
+  let result = panic::catch_unwind(AssertUnwindSafe(|| this.get().unwrap().resume(())));
+  assert!(result.is_err());
+  assert_eq!(this.get().unwrap().state(), State::Panicked);
+}
+
+#[test]
+fn panic_propagated() {
+  let coroutine = Coroutine::new(OsStack::new(0).unwrap(), |_, ()| {
+    panic!("foo")
+  });
+  let payload = panic::catch_unwind(AssertUnwindSafe(|| coroutine.resume(()))).unwrap_err();
+  assert_eq!(*payload.downcast::<&str>().unwrap(), "foo");
+  assert_eq!(coroutine.state(), State::Panicked);
+}
+
+#[test]
+fn unwound_on_drop() {
+  let dropped = Rc::new(Cell::new(false));
+  let coroutine = {
+    let dropped = dropped.clone();
+    Coroutine::new(OsStack::new(0).unwrap(), move |current, ()| {
+      let _flag = SetOnDrop(dropped);
+      loop { current.suspend(()) }
+    })
+  };
+  coroutine.resume(());
+  assert!(!dropped.get());
+  drop(coroutine);
+  assert!(dropped.get());
+}
+
+#[test]
+fn dropped_before_start() {
+  let dropped = Rc::new(Cell::new(false));
+  let flag = SetOnDrop(dropped.clone());
+  let coroutine = Coroutine::new(OsStack::new(0).unwrap(), move |_, ()| {
+    drop(flag)
+  });
+  drop(coroutine);
+  assert!(dropped.get());
+}
+
+#[test]
+fn try_new_aligned_environment_too_large() {
+  #[repr(align(4096))]
+  struct Aligned(u8);
+
+  // The environment fits, but not once it is aligned.
+  let aligned = Aligned(42);
+  let stack = OsStack::new(fringe::MIN_STACK_SIZE + 8192).unwrap();
+  match Coroutine::try_new(stack, move |_current, value: u8| value + aligned.0) {
+    Err(StackError::TooSmall { min, .. }) => assert!(min > fringe::MIN_STACK_SIZE + 8192),
+    Err(err) => panic!("unexpected error: {}", err),
+    Ok(_) => panic!("coroutine created with an environment larger than its stack")
+  }
+}
+
+#[test]
+fn drop_unguarded_leaks() {
+  let dropped = Rc::new(Cell::new(false));
+  let flag = SetOnDrop(dropped.clone());
+  let coroutine = unsafe {
+    Coroutine::unsafe_new(OwnedStack::new(16 << 10), move |current, value: i32| {
+      let _flag = flag;
+      current.suspend(value)
+    })
+  };
+  assert_eq!(coroutine.resume(1), 1);
+  drop(coroutine);
+  assert!(!dropped.get());
+}
@@ -6,16 +6,12 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 #![cfg(target_os = "linux")]
-#![feature(test)]
-#![feature(thread_local)]
-#![feature(asm)]
 extern crate fringe;
-extern crate test;
 use fringe::{OsStack, Generator};
-use test::black_box;
+use std::hint::black_box;
 
 const FE_DIVBYZERO: i32 = 0x4;
-extern {
+extern "C" {
   fn feenableexcept(except: i32) -> i32;
 }
 
@@ -7,8 +7,17 @@
 // copied, modified, or distributed except according to those terms.
 extern crate fringe;
 
-use fringe::{SliceStack, OwnedStack, OsStack};
-use fringe::generator::{Generator, Yielder};
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::process::Command;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use fringe::{SliceStack, OwnedStack, OsStack, Stack, GuardedStack, StackError, SendGenerator};
+use fringe::generator::{Generator, GeneratorState, State, Yielder, Lend};
 
 fn add_one_fn(yielder: &mut Yielder<i32, i32>, mut input: i32) {
   loop {
@@ -62,7 +71,7 @@ fn panic_safety() {
     panic!("foo")
   });
 
-  let mut wrapper = Wrapper { gen: gen };
+  let mut wrapper = Wrapper { gen };
   wrapper.gen.resume(());
 }
 
@@ -108,3 +117,633 @@ fn forget_yielded() {
   generator.resume(());
   generator.resume(());
 }
+
+struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+  fn drop(&mut self) {
+    self.0.store(true, Ordering::SeqCst)
+  }
+}
+
+#[test]
+fn drop_unwinds() {
+  let dropped = Arc::new(AtomicBool::new(false));
+  let flag = DropFlag(dropped.clone());
+
+  let stack = OsStack::new(1 << 16).unwrap();
+  let mut generator = Generator::new(stack, move |yielder, ()| {
+    let _flag = flag;
+    loop { yielder.suspend(()) }
+  });
+  generator.resume(());
+  assert!(!dropped.load(Ordering::SeqCst));
+  drop(generator);
+  assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn drop_unguarded_leaks() {
+  let dropped = Arc::new(AtomicBool::new(false));
+  let flag = DropFlag(dropped.clone());
+
+  let stack = OwnedStack::new(1024);
+  let mut generator = unsafe {
+    Generator::unsafe_new(stack, move |yielder: &mut Yielder<(), ()>, ()| {
+      let _flag = flag;
+      loop { yielder.suspend(()) }
+    })
+  };
+  generator.resume(());
+  drop(generator);
+  assert!(!dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+fn cancel_before_start() {
+  let dropped = Arc::new(AtomicBool::new(false));
+  let flag = DropFlag(dropped.clone());
+
+  let stack = OsStack::new(1 << 16).unwrap();
+  let mut generator = Generator::new(stack, move |yielder, ()| {
+    let _flag = flag;
+    yielder.suspend(())
+  });
+  generator.cancel();
+  assert!(dropped.load(Ordering::SeqCst));
+  assert_eq!(generator.resume(()), None);
+  generator.unwrap();
+}
+
+#[test]
+fn suspend_while_cancelling() {
+  struct SuspendOnDrop<'a>(&'a Yielder<(), i32>);
+
+  impl<'a> Drop for SuspendOnDrop<'a> {
+    fn drop(&mut self) {
+      let _ = panic::catch_unwind(AssertUnwindSafe(|| self.0.suspend(2)));
+    }
+  }
+
+  let stack = OsStack::new(1 << 16).unwrap();
+  let mut generator = Generator::new(stack, move |yielder, ()| {
+    let _suspender = SuspendOnDrop(yielder);
+    yielder.suspend(1);
+  });
+  assert_eq!(generator.resume(()), Some(1));
+  generator.cancel();
+  generator.unwrap();
+}
+
+#[test]
+fn suspend_from_destructor_while_cancelling() {
+  struct SuspendOnDrop<'a>(&'a Yielder<(), i32>);
+
+  impl<'a> Drop for SuspendOnDrop<'a> {
+    fn drop(&mut self) {
+      self.0.suspend(2);
+    }
+  }
+
+  // The abort kills the process, so the test runs itself in a child process
+  // and inspects its fate.
+  if env::var_os("FRINGE_ABORT_CHILD").is_some() {
+    let stack = OsStack::new(0).unwrap();
+    let mut generator = Generator::new(stack, move |yielder, ()| {
+      let _suspender = SuspendOnDrop(yielder);
+      yielder.suspend(1);
+    });
+    assert_eq!(generator.resume(()), Some(1));
+    generator.cancel();
+    unreachable!()
+  }
+
+  let output = Command::new(env::current_exe().unwrap())
+    .args(["suspend_from_destructor_while_cancelling", "--exact", "--nocapture", "--test-threads=1"])
+    .env("FRINGE_ABORT_CHILD", "1")
+    .output()
+    .unwrap();
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(!output.status.success(), "stderr: {}", stderr);
+  assert!(stderr.contains("panic in a destructor during cleanup"), "stderr: {}", stderr);
+}
+
+#[test]
+fn return_value() {
+  let stack = OsStack::new(0).unwrap();
+  let mut sum = Generator::new(stack, |yielder, mut input| {
+    let mut total = 0;
+    while input != 0 {
+      total += input;
+      input = yielder.suspend(total)
+    }
+    vec![total]
+  });
+  assert_eq!(sum.resume_state(1), GeneratorState::Yielded(1));
+  assert_eq!(sum.resume_state(2), GeneratorState::Yielded(3));
+  assert_eq!(sum.resume_state(0), GeneratorState::Complete(vec![3]));
+  assert_eq!(sum.resume(1), None);
+}
+
+#[test]
+fn resume_discards_return_value() {
+  let dropped = Arc::new(AtomicBool::new(false));
+  let flag = DropFlag(dropped.clone());
+
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, move |_yielder: &mut Yielder<(), ()>, ()| flag);
+  assert_eq!(generator.resume(()), None);
+  assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+#[should_panic]
+fn resume_state_after_complete() {
+  let mut add_one = new_add_one();
+  assert_eq!(add_one.resume_state(0), GeneratorState::Complete(()));
+  add_one.resume_state(0);
+}
+
+#[test]
+fn resume_catching() {
+  let stack = OsStack::new(1 << 16).unwrap();
+  let mut generator = Generator::new(stack, |yielder, ()| {
+    yielder.suspend(1);
+    panic!("oops")
+  });
+  assert_eq!(generator.resume_catching(()).unwrap(), GeneratorState::Yielded(1));
+  assert_eq!(generator.state(), State::Runnable);
+  let payload = generator.resume_catching(()).unwrap_err();
+  assert_eq!(payload.downcast_ref::<&str>(), Some(&"oops"));
+  assert_eq!(generator.state(), State::Panicked);
+  assert_eq!(generator.resume(()), None);
+  generator.unwrap();
+}
+
+#[test]
+fn panic_propagates() {
+  let stack = OsStack::new(1 << 16).unwrap();
+  let mut generator = Generator::new(stack, |_yielder: &mut Yielder<(), ()>, ()| {
+    panic!("oops")
+  });
+  let payload = panic::catch_unwind(AssertUnwindSafe(|| generator.resume(()))).unwrap_err();
+  assert_eq!(payload.downcast_ref::<&str>(), Some(&"oops"));
+  assert_eq!(generator.state(), State::Panicked);
+}
+
+#[test]
+fn complete_is_not_panicked() {
+  let mut add_one = new_add_one();
+  assert_eq!(add_one.resume_catching(0).unwrap(), GeneratorState::Complete(()));
+  assert_eq!(add_one.state(), State::Unavailable);
+}
+
+#[test]
+fn panic_propagates_through_nested_generators() {
+  let stack = OsStack::new(1 << 16).unwrap();
+  let mut outer = Generator::new(stack, |yielder, ()| {
+    let stack = OsStack::new(1 << 16).unwrap();
+    let mut inner = Generator::new(stack, |_yielder: &mut Yielder<(), ()>, ()| {
+      panic!("inner")
+    });
+    yielder.suspend(());
+    inner.resume(());
+  });
+  assert_eq!(outer.resume(()), Some(()));
+  let payload = panic::catch_unwind(AssertUnwindSafe(|| outer.resume(()))).unwrap_err();
+  assert_eq!(payload.downcast_ref::<&str>(), Some(&"inner"));
+  assert_eq!(outer.state(), State::Panicked);
+}
+
+#[test]
+fn try_new_too_small() {
+  let stack = OsStack::new(4096).unwrap();
+  let size = stack.base() as usize - stack.limit() as usize;
+  match Generator::try_new(stack, add_one_fn) {
+    Err(err) => assert_eq!(err, StackError::TooSmall { size, min: fringe::MIN_STACK_SIZE }),
+    Ok(_) => panic!("generator created on a {}-byte stack", size)
+  }
+}
+
+#[test]
+fn try_new_misaligned() {
+  struct MisalignedStack(OsStack);
+
+  impl Stack for MisalignedStack {
+    fn base(&self) -> *mut u8 { unsafe { self.0.base().offset(-1) } }
+    fn limit(&self) -> *mut u8 { self.0.limit() }
+  }
+
+  unsafe impl GuardedStack for MisalignedStack {}
+
+  let stack = MisalignedStack(OsStack::new(0).unwrap());
+  assert_eq!(Generator::try_new(stack, add_one_fn).err(), Some(StackError::Misaligned));
+}
+
+#[test]
+fn try_new_guard_too_small() {
+  struct ThinGuardStack(OsStack);
+
+  impl Stack for ThinGuardStack {
+    fn base(&self) -> *mut u8 { self.0.base() }
+    fn limit(&self) -> *mut u8 { self.0.limit() }
+  }
+
+  unsafe impl GuardedStack for ThinGuardStack {
+    fn guard_size(&self) -> usize { fringe::MIN_GUARD_SIZE - 1 }
+  }
+
+  let stack = ThinGuardStack(OsStack::new(0).unwrap());
+  assert_eq!(Generator::try_new(stack, add_one_fn).err(),
+             Some(StackError::GuardTooSmall { size: fringe::MIN_GUARD_SIZE - 1, min: fringe::MIN_GUARD_SIZE }));
+}
+
+#[test]
+#[should_panic(expected = "cannot create generator")]
+fn new_too_small() {
+  let stack = OsStack::new(4096).unwrap();
+  Generator::new(stack, add_one_fn);
+}
+
+#[test]
+fn not_send() {
+  let shared = Rc::new(RefCell::new(vec![1, 2]));
+  let captured = shared.clone();
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, move |yielder, ()| {
+    let mut items = captured.borrow_mut();
+    items.push(3);
+    let item = Rc::new(items.len());
+    yielder.suspend(item);
+  });
+  assert_eq!(generator.resume(()).map(|item| *item), Some(3));
+  assert!(shared.try_borrow().is_err());
+  assert_eq!(generator.resume(()), None);
+  assert_eq!(*shared.borrow(), vec![1, 2, 3]);
+}
+
+#[test]
+fn send_generator() {
+  let stack = OsStack::new(0).unwrap();
+  let mut add_one = unsafe { SendGenerator::new(stack, add_one_fn) };
+  assert_eq!(add_one.resume(1), Some(2));
+  let mut add_one = thread::spawn(move || {
+    assert_eq!(add_one.resume(2), Some(3));
+    add_one
+  }).join().unwrap();
+  assert_eq!(add_one.resume(0), None);
+  assert_eq!(add_one.state(), State::Unavailable);
+}
+
+#[test]
+fn send_generator_migration_check() {
+  let stack = OsStack::new(0).unwrap();
+  let mut add_one = unsafe { SendGenerator::new(stack, add_one_fn) };
+  add_one.set_migration_check(true);
+  assert_eq!(add_one.resume(1), Some(2));
+  let result = thread::spawn(move || {
+    panic::catch_unwind(AssertUnwindSafe(|| add_one.resume(2))).map_err(|payload| {
+      payload.downcast_ref::<String>().cloned().unwrap()
+    })
+  }).join().unwrap();
+  assert!(result.unwrap_err().contains("resumed on thread"));
+}
+
+fn tokenize(yielder: &mut Yielder<&'static str, Lend<str>>, mut input: &'static str) -> usize {
+  // Tokens are copied into a buffer on the generator stack and lent out of it.
+  let mut buffer = [0u8; 8];
+  let mut count = 0;
+  while !input.is_empty() {
+    for word in input.split_whitespace() {
+      buffer[..word.len()].copy_from_slice(word.as_bytes());
+      count += 1;
+      input = yielder.suspend_ref(std::str::from_utf8(&buffer[..word.len()]).unwrap());
+    }
+  }
+  count
+}
+
+#[test]
+fn lending() {
+  let stack = OsStack::new(0).unwrap();
+  let mut tokens = Generator::new(stack, tokenize);
+  assert_eq!(tokens.resume_ref("foo bar"), Some("foo"));
+  assert_eq!(tokens.resume_ref(""), Some("bar"));
+  assert_eq!(tokens.resume_state_ref(""), GeneratorState::Complete(2));
+  assert_eq!(tokens.resume_ref(""), None);
+}
+
+#[test]
+fn lending_reuses_buffer() {
+  let stack = OsStack::new(0).unwrap();
+  let mut tokens = Generator::new(stack, tokenize);
+  let first = tokens.resume_ref("quux zap").map(str::to_owned);
+  let second = tokens.resume_ref("").map(str::to_owned);
+  assert_eq!(first.as_deref(), Some("quux"));
+  assert_eq!(second.as_deref(), Some("zap"));
+}
+
+#[test]
+fn reset() {
+  let mut add_one = new_add_one();
+  assert_eq!(add_one.resume(1), Some(2));
+  assert_eq!(add_one.resume(0), None);
+  add_one.reset(add_one_fn);
+  assert_eq!(add_one.state(), State::Runnable);
+  assert_eq!(add_one.resume(2), Some(3));
+  assert_eq!(add_one.resume(0), None);
+}
+
+#[test]
+fn reset_after_panic() {
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, |_yielder: &mut Yielder<(), ()>, ()| {
+    panic!("oops")
+  });
+  assert!(generator.resume_catching(()).is_err());
+  generator.reset(|yielder, ()| yielder.suspend(()));
+  assert_eq!(generator.resume(()), Some(()));
+}
+
+#[test]
+#[should_panic(expected = "cannot reset a generator that has not finished")]
+fn reset_runnable() {
+  let mut add_one = new_add_one();
+  assert_eq!(add_one.resume(1), Some(2));
+  add_one.reset(add_one_fn);
+}
+
+#[test]
+#[should_panic(expected = "cannot reset generator: stack of")]
+fn reset_stack_too_small() {
+  let table = [0u8; 30 << 10];
+  let stack = OsStack::new(36 << 10).unwrap();
+  let mut generator = Generator::new(stack, |_yielder: &mut Yielder<(), u8>, ()| {});
+  assert_eq!(generator.resume(()), None);
+  generator.reset(move |yielder, ()| yielder.suspend(table[0]));
+}
+
+#[test]
+fn recycle() {
+  let mut add_one = new_add_one();
+  assert_eq!(add_one.resume(0), None);
+  let mut greeting = add_one.recycle(|yielder, name: &'static str| {
+    yielder.suspend(format!("hello, {}", name));
+    name.len()
+  });
+  assert_eq!(greeting.resume_state("world"), GeneratorState::Yielded("hello, world".to_owned()));
+  assert_eq!(greeting.resume_state("again"), GeneratorState::Complete(5));
+}
+
+#[test]
+#[should_panic(expected = "cannot recycle generator: stack of")]
+fn recycle_stack_too_small() {
+  let table = [0u8; 30 << 10];
+  let stack = OsStack::new(36 << 10).unwrap();
+  let mut generator = Generator::new(stack, |_yielder: &mut Yielder<(), ()>, ()| {});
+  assert_eq!(generator.resume(()), None);
+  generator.recycle(move |yielder: &mut Yielder<(), u8>, ()| yielder.suspend(table[0]));
+}
+
+#[test]
+fn large_environment() {
+  let table = [7u8; 4096];
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, move |yielder, ()| {
+    yielder.suspend(table.iter().map(|&x| x as usize).sum::<usize>())
+  });
+  assert_eq!(generator.resume(()), Some(7 * 4096));
+}
+
+#[test]
+fn aligned_environment() {
+  #[repr(align(256))]
+  struct Aligned(u8);
+
+  let aligned = Aligned(42);
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, move |yielder, ()| {
+    yielder.suspend((&aligned as *const Aligned as usize % 256, aligned.0))
+  });
+  assert_eq!(generator.resume(()), Some((0, 42)));
+}
+
+#[test]
+fn try_new_environment_too_large() {
+  let table = [0u8; 1 << 20];
+  let stack = OsStack::new(0).unwrap();
+  let size = stack.base() as usize - stack.limit() as usize;
+  match Generator::try_new(stack, move |yielder: &mut Yielder<(), u8>, ()| yielder.suspend(table[0])) {
+    Err(err) => assert_eq!(err, StackError::TooSmall { size, min: fringe::MIN_STACK_SIZE + (1 << 20) }),
+    Ok(_) => panic!("generator created with an environment larger than its stack")
+  }
+}
+
+fn round_trip<T: Clone + PartialEq + std::fmt::Debug + 'static>(value: T) {
+  let stack = OsStack::new(0).unwrap();
+  let mut identity = Generator::new(stack, |yielder, mut input: T| {
+    loop { input = yielder.suspend(input) }
+  });
+  assert_eq!(identity.resume(value.clone()), Some(value.clone()));
+  assert_eq!(identity.resume(value.clone()), Some(value));
+}
+
+#[test]
+fn payload_sizes() {
+  // Small payloads are passed in registers, and larger or overaligned ones through memory.
+  round_trip(());
+  round_trip(42u8);
+  round_trip(-42i32);
+  round_trip((1u8, 2u32));
+  round_trip((1usize, 2usize));
+  round_trip([1usize, 2, 3]);
+  round_trip([1usize, 2, 3, 4, 5]);
+  round_trip(u128::MAX - 1);
+  round_trip("hello".to_owned());
+  round_trip(vec![String::from("a"), String::from("b")]);
+}
+
+#[test]
+fn delegate() {
+  let stack = OsStack::new(0).unwrap();
+  let mut outer = Generator::new(stack, |yielder, input: i32| {
+    let mut inner = new_add_one();
+    let input = yielder.suspend(input * 10);
+    yielder.delegate(&mut inner, input);
+    assert_eq!(inner.state(), State::Unavailable);
+    yielder.suspend(-1);
+  });
+  assert_eq!(outer.resume(1), Some(10));
+  assert_eq!(outer.resume(2), Some(3));
+  assert_eq!(outer.resume(5), Some(6));
+  assert_eq!(outer.resume(0), Some(-1));
+  assert_eq!(outer.resume(0), None);
+}
+
+#[test]
+fn delegate_return_value() {
+  let stack = OsStack::new(0).unwrap();
+  let mut outer = Generator::new(stack, |yielder: &mut Yielder<String, String>, input| {
+    let stack = OsStack::new(0).unwrap();
+    let mut inner = Generator::new(stack, |yielder: &mut Yielder<String, String>, mut input| {
+      input = yielder.suspend(input + "!");
+      input.len()
+    });
+    // Start the inner generator before delegating to it.
+    let input = yielder.suspend(inner.resume(input).unwrap());
+    let len = yielder.delegate(&mut inner, input);
+    format!("{}", len)
+  });
+  assert_eq!(outer.resume_state("a".to_owned()), GeneratorState::Yielded("a!".to_owned()));
+  assert_eq!(outer.resume_state("abc".to_owned()), GeneratorState::Complete("3".to_owned()));
+}
+
+struct Tree(u32, Vec<Tree>);
+
+fn walk(tree: Rc<Tree>, path: Vec<usize>) -> Generator<(), u32, OsStack, usize> {
+  let stack = OsStack::new(0).unwrap();
+  Generator::new(stack, move |yielder, ()| {
+    let mut node = &*tree;
+    for &index in &path { node = &node.1[index] }
+    yielder.suspend(node.0);
+    let mut count = 1;
+    for index in 0..node.1.len() {
+      let mut path = path.clone();
+      path.push(index);
+      count += yielder.delegate(&mut walk(tree.clone(), path), ());
+    }
+    count
+  })
+}
+
+#[test]
+fn delegate_nested() {
+  let tree = Rc::new(Tree(1, vec![
+    Tree(2, vec![Tree(3, vec![Tree(4, vec![])])]),
+    Tree(5, vec![Tree(6, vec![]), Tree(7, vec![])])
+  ]));
+  let mut walker = walk(tree, vec![]);
+  let mut values = vec![];
+  let count = loop {
+    match walker.resume_state(()) {
+      GeneratorState::Yielded(value) => values.push(value),
+      GeneratorState::Complete(count) => break count
+    }
+  };
+  assert_eq!(values, vec![1, 2, 3, 4, 5, 6, 7]);
+  assert_eq!(count, 7);
+}
+
+#[test]
+fn delegate_panic() {
+  let stack = OsStack::new(0).unwrap();
+  let mut outer = Generator::new(stack, |yielder: &mut Yielder<(), i32>, ()| {
+    let stack = OsStack::new(0).unwrap();
+    let mut inner = Generator::new(stack, |yielder: &mut Yielder<(), i32>, ()| {
+      yielder.suspend(1);
+      panic!("inner")
+    });
+    let result = panic::catch_unwind(AssertUnwindSafe(|| yielder.delegate(&mut inner, ())));
+    assert_eq!(inner.state(), State::Panicked);
+    yielder.suspend(if result.is_err() { 2 } else { 0 })
+  });
+  assert_eq!(outer.resume(()), Some(1));
+  assert_eq!(outer.resume(()), Some(2));
+  assert_eq!(outer.resume(()), None);
+}
+
+#[test]
+fn delegate_cancel() {
+  let outer_dropped = Arc::new(AtomicBool::new(false));
+  let inner_dropped = Arc::new(AtomicBool::new(false));
+  let outer_flag = DropFlag(outer_dropped.clone());
+  let inner_flag = DropFlag(inner_dropped.clone());
+
+  let stack = OsStack::new(0).unwrap();
+  let mut outer = Generator::new(stack, move |yielder, ()| {
+    let _flag = outer_flag;
+    let stack = OsStack::new(0).unwrap();
+    let mut inner = Generator::new(stack, move |yielder, ()| {
+      let _flag = inner_flag;
+      loop { yielder.suspend(()) }
+    });
+    yielder.delegate(&mut inner, ())
+  });
+  outer.resume(());
+  outer.resume(());
+  assert!(!inner_dropped.load(Ordering::SeqCst));
+  drop(outer);
+  assert!(inner_dropped.load(Ordering::SeqCst));
+  assert!(outer_dropped.load(Ordering::SeqCst));
+}
+
+#[derive(Debug, PartialEq)]
+struct Timeout;
+
+#[test]
+fn throw() {
+  let stack = OsStack::new(0).unwrap();
+  let mut exchange = Generator::new(stack, |yielder: &mut Yielder<i32, &'static str>, mut input| {
+    let mut received = vec![input];
+    loop {
+      match yielder.try_suspend::<Timeout>("more") {
+        Ok(next) => { input = next; received.push(input) }
+        Err(Timeout) => { yielder.suspend("aborted"); break }
+      }
+    }
+    received
+  });
+  assert_eq!(exchange.resume_state(1), GeneratorState::Yielded("more"));
+  assert_eq!(exchange.resume_state(2), GeneratorState::Yielded("more"));
+  assert_eq!(exchange.throw_state(Timeout), GeneratorState::Yielded("aborted"));
+  assert_eq!(exchange.resume_state(0), GeneratorState::Complete(vec![1, 2]));
+}
+
+#[test]
+fn throw_into_suspend() {
+  let dropped = Arc::new(AtomicBool::new(false));
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, |yielder: &mut Yielder<(), ()>, ()| {
+    yielder.suspend(())
+  });
+  generator.resume(());
+  let result = panic::catch_unwind(AssertUnwindSafe(|| generator.throw(DropFlag(dropped.clone()))));
+  assert!(result.is_err());
+  assert!(dropped.load(Ordering::SeqCst));
+  assert_eq!(generator.state(), State::Panicked);
+}
+
+#[test]
+fn throw_unexpected_type() {
+  let dropped = Arc::new(AtomicBool::new(false));
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, |yielder: &mut Yielder<(), ()>, ()| {
+    let _ = yielder.try_suspend::<Timeout>(());
+  });
+  generator.resume(());
+  let result = panic::catch_unwind(AssertUnwindSafe(|| generator.throw(DropFlag(dropped.clone()))));
+  assert!(result.is_err());
+  assert!(dropped.load(Ordering::SeqCst));
+}
+
+#[test]
+#[should_panic(expected = "cannot throw into a generator that has not started")]
+fn throw_before_start() {
+  let mut generator = new_add_one();
+  generator.throw(Timeout);
+}
+
+#[test]
+fn throw_into_delegate() {
+  let stack = OsStack::new(0).unwrap();
+  let mut outer = Generator::new(stack, |yielder: &mut Yielder<(), bool>, ()| {
+    let stack = OsStack::new(0).unwrap();
+    let mut inner = Generator::new(stack, |yielder: &mut Yielder<(), bool>, ()| {
+      yielder.try_suspend::<Timeout>(false).is_err()
+    });
+    let timed_out = yielder.delegate(&mut inner, ());
+    yielder.suspend(timed_out)
+  });
+  assert_eq!(outer.resume(()), Some(false));
+  assert_eq!(outer.throw(Timeout), Some(true));
+}
@@ -0,0 +1,92 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(feature = "hardening")]
+extern crate fringe;
+
+use std::hint::black_box;
+use std::ptr;
+
+use fringe::{Stack, SliceStack, OsStack, Generator};
+use fringe::generator::Yielder;
+
+// The generator stack is the upper part of the memory, so that an overflow, as well as
+// the panic reporting it, only ever runs into the lower part.
+#[repr(align(16))]
+struct Memory([u8; 1 << 18]);
+
+fn memory() -> Box<Memory> {
+  Box::new(Memory([0; 1 << 18]))
+}
+
+fn stack(memory: &mut Memory) -> SliceStack<'_> {
+  SliceStack(&mut memory.0[(1 << 18) - (1 << 15)..])
+}
+
+#[test]
+fn no_false_positives() {
+  let mut inner = Generator::new(OsStack::new(0).unwrap(), |yielder: &mut Yielder<u32, u32>, input| {
+    yielder.suspend(input + 1) + 1
+  });
+  let mut outer = Generator::new(OsStack::new(0).unwrap(), move |yielder, input| {
+    let value = yielder.delegate(&mut inner, input);
+    yielder.suspend(value)
+  });
+  assert_eq!(outer.resume(1), Some(2));
+  assert_eq!(outer.resume(2), Some(3));
+  assert_eq!(outer.resume(3), None);
+  outer.reset(|yielder, input| yielder.suspend(input));
+  assert_eq!(outer.resume(4), Some(4));
+}
+
+#[test]
+#[should_panic(expected = "stack canary at")]
+fn canary_overwritten() {
+  let mut memory = memory();
+  let stack = stack(&mut memory);
+  let limit = stack.limit() as usize;
+  let f = |yielder: &mut Yielder<usize, ()>, limit: usize| {
+    unsafe { ptr::write_bytes(limit as *mut u8, 0, 4) }
+    yielder.suspend(());
+  };
+  let mut generator = unsafe { Generator::unsafe_new(stack, f) };
+  generator.resume(limit);
+}
+
+#[test]
+#[should_panic(expected = "stack canary at")]
+fn overflow_detected() {
+  let mut memory = memory();
+  let mut generator = unsafe {
+    Generator::unsafe_new(stack(&mut memory), |yielder: &mut Yielder<(), ()>, ()| {
+      let frame = black_box([1u8; 40 << 10]);
+      yielder.suspend(());
+      black_box(frame);
+    })
+  };
+  generator.resume(());
+}
+
+#[test]
+#[should_panic(expected = "CFA slot at")]
+fn cfa_slot_overwritten() {
+  let mut memory = memory();
+  let stack = stack(&mut memory);
+  let base = stack.base() as usize;
+  // The generator function captures nothing, so the initial frame starts at the stack base.
+  let f = |yielder: &mut Yielder<usize, ()>, base: usize| {
+    #[cfg(target_arch = "x86")]
+    let slot = (base as *mut usize).wrapping_offset(-6);
+    #[cfg(target_arch = "or1k")]
+    let slot = (base as *mut usize).wrapping_offset(-2);
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    let slot = (base as *mut usize).wrapping_offset(-4);
+    unsafe { *slot = 0 }
+    yielder.suspend(());
+  };
+  let mut generator = unsafe { Generator::unsafe_new(stack, f) };
+  generator.resume(base);
+}
@@ -0,0 +1,99 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+extern crate fringe;
+
+use std::panic;
+
+use fringe::{OsStack, Generator, Coroutine};
+use fringe::generator::Yielder;
+
+fn visit(depth: u32, callback: &mut dyn FnMut(u32) -> u32) -> u32 {
+  if depth == 0 { return 0 }
+  callback(depth) + visit(depth - 1, callback)
+}
+
+#[test]
+fn suspend_from_callback() {
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, |yielder: &mut Yielder<u32, u32>, _| {
+    yielder.make_current();
+    visit(3, &mut |depth| fringe::suspend::<u32, u32>(depth))
+  });
+  assert_eq!(generator.resume(0), Some(3));
+  assert_eq!(generator.resume(10), Some(2));
+  assert_eq!(generator.resume(20), Some(1));
+  assert_eq!(generator.resume(30), None);
+}
+
+#[test]
+fn outside_generator() {
+  assert!(!fringe::can_suspend::<(), ()>());
+  assert!(panic::catch_unwind(|| fringe::suspend::<(), ()>(())).is_err());
+
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, |yielder, ()| {
+    yielder.make_current();
+    loop { yielder.suspend(fringe::can_suspend::<(), bool>()) }
+  });
+  assert_eq!(generator.resume(()), Some(true));
+  // The resumer is not a generator.
+  assert!(!fringe::can_suspend::<(), bool>());
+  assert_eq!(generator.resume(()), Some(true));
+}
+
+#[test]
+fn type_mismatch() {
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, |yielder, ()| {
+    yielder.make_current();
+    let mismatched = !fringe::can_suspend::<(), u32>() && !fringe::can_suspend::<u32, i32>();
+    let panicked = panic::catch_unwind(|| fringe::suspend::<(), u32>(1)).is_err();
+    yielder.suspend(mismatched && panicked)
+  });
+  assert_eq!(generator.resume(()), Some(true));
+}
+
+#[test]
+fn not_made_current() {
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, |yielder, ()| {
+    yielder.suspend(fringe::can_suspend::<(), bool>())
+  });
+  assert_eq!(generator.resume(()), Some(false));
+}
+
+#[test]
+fn innermost_only() {
+  let stack = OsStack::new(0).unwrap();
+  let mut outer = Generator::new(stack, |yielder, ()| {
+    yielder.make_current();
+    let stack = OsStack::new(0).unwrap();
+    let mut inner = Generator::new(stack, |yielder, ()| {
+      yielder.suspend(fringe::can_suspend::<(), bool>())
+    });
+    // The outer generator cannot be suspended from the inner one...
+    let inner_sees_outer = inner.resume(()).unwrap();
+    // ... but is current again once the inner one suspends.
+    fringe::suspend::<(), bool>(!inner_sees_outer)
+  });
+  assert_eq!(outer.resume(()), Some(true));
+  assert_eq!(outer.resume(()), None);
+}
+
+#[test]
+fn not_visible_from_coroutine() {
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, |yielder, ()| {
+    yielder.make_current();
+    let coroutine = Coroutine::new(OsStack::new(0).unwrap(), |_, ()| {
+      assert!(!fringe::can_suspend::<(), bool>())
+    });
+    coroutine.resume(());
+    yielder.suspend(fringe::can_suspend::<(), bool>())
+  });
+  assert_eq!(generator.resume(()), Some(true));
+}
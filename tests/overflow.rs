@@ -0,0 +1,171 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(unix)]
+extern crate fringe;
+
+use std::env;
+use std::panic::{self, AssertUnwindSafe};
+use std::hint::black_box;
+use std::process::Command;
+use std::ptr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::os::unix::process::ExitStatusExt;
+
+use fringe::{Stack, OsStack, Generator};
+use fringe::generator::{Yielder, State, StackOverflow};
+
+fn recurse(depth: usize) -> usize {
+  let frame = black_box([depth; 64]);
+  if black_box(false) { return 0 }
+  frame[0] + recurse(depth + 1)
+}
+
+fn overflow(_yielder: &mut Yielder<(), ()>, _: ()) {
+  recurse(0);
+}
+
+// The overflow kills the process, so the test runs itself in a child process
+// and inspects its fate.
+fn run_child(test: &str) -> (Option<i32>, String) {
+  let output = Command::new(env::current_exe().unwrap())
+    .args([test, "--exact", "--nocapture", "--test-threads=1"])
+    .env("FRINGE_OVERFLOW_CHILD", "1")
+    .output()
+    .unwrap();
+  (output.status.signal(), String::from_utf8_lossy(&output.stderr).into_owned())
+}
+
+#[test]
+fn overflow_reported() {
+  if env::var_os("FRINGE_OVERFLOW_CHILD").is_some() {
+    fringe::overflow::install_handler().unwrap();
+    let stack = OsStack::new(0).unwrap();
+    let mut generator = Generator::new(stack, overflow);
+    generator.resume(());
+    unreachable!()
+  }
+
+  let (signal, stderr) = run_child("overflow_reported");
+  assert_eq!(signal, Some(6 /* SIGABRT */), "stderr: {}", stderr);
+  assert!(stderr.contains("generator `overflow::overflow` has overflowed its stack"),
+          "stderr: {}", stderr);
+}
+
+#[test]
+fn other_faults_chained() {
+  if env::var_os("FRINGE_OVERFLOW_CHILD").is_some() {
+    fringe::overflow::install_handler().unwrap();
+    let stack = OsStack::new(0).unwrap();
+    let _generator = Generator::new(stack, overflow);
+    unsafe { ptr::write_volatile(black_box(ptr::dangling_mut::<usize>()), 0) };
+    unreachable!()
+  }
+
+  let (signal, stderr) = run_child("other_faults_chained");
+  assert_eq!(signal, Some(11 /* SIGSEGV */), "stderr: {}", stderr);
+  assert!(!stderr.contains("has overflowed its stack"), "stderr: {}", stderr);
+}
+
+#[test]
+fn overflow_recovered() {
+  fringe::overflow::install_handler().unwrap();
+  fringe::overflow::set_recoverable(true);
+
+  let stack = OsStack::new(0).unwrap();
+  let mut generator = Generator::new(stack, |yielder: &mut Yielder<(), i32>, ()| {
+    yielder.suspend(1);
+    recurse(0);
+  });
+  assert_eq!(generator.try_resume(()), Ok(Some(1)));
+  assert_eq!(generator.try_resume(()), Err(StackOverflow));
+  assert_eq!(generator.state(), State::Poisoned);
+  assert_eq!(generator.try_resume(()), Err(StackOverflow));
+  assert_eq!(generator.resume(()), None);
+
+  // The stack can be reused once the generator is abandoned.
+  let stack = generator.unwrap();
+  let mut generator = Generator::new(stack, |yielder: &mut Yielder<(), i32>, ()| {
+    yielder.suspend(2);
+  });
+  assert_eq!(generator.try_resume(()), Ok(Some(2)));
+  assert_eq!(generator.try_resume(()), Ok(None));
+}
+
+#[test]
+fn overflow_recovered_while_registering() {
+  fringe::overflow::install_handler().unwrap();
+  fringe::overflow::set_recoverable(true);
+
+  // Another thread keeps registering and unregistering stacks meanwhile.
+  let done = Arc::new(AtomicBool::new(false));
+  let churn = {
+    let done = done.clone();
+    thread::spawn(move || while !done.load(Ordering::Relaxed) {
+      drop(Generator::new(OsStack::new(0).unwrap(), overflow))
+    })
+  };
+  for _ in 0..100 {
+    let mut generator = Generator::new(OsStack::new(0).unwrap(), overflow);
+    assert_eq!(generator.try_resume(()), Err(StackOverflow));
+  }
+  done.store(true, Ordering::Relaxed);
+  churn.join().unwrap();
+}
+
+#[test]
+fn nested_overflow_recovered() {
+  fringe::overflow::install_handler().unwrap();
+  fringe::overflow::set_recoverable(true);
+
+  let stack = OsStack::new(0).unwrap();
+  let mut outer = Generator::new(stack, |yielder: &mut Yielder<(), bool>, ()| {
+    let stack = OsStack::new(0).unwrap();
+    let mut inner = Generator::new(stack, overflow);
+    let overflowed = inner.try_resume(()) == Err(StackOverflow);
+    yielder.suspend(overflowed);
+  });
+  assert_eq!(outer.try_resume(()), Ok(Some(true)));
+  assert_eq!(outer.try_resume(()), Ok(None));
+}
+
+#[test]
+fn delegated_overflow_recovered() {
+  fringe::overflow::install_handler().unwrap();
+  fringe::overflow::set_recoverable(true);
+
+  let stack = OsStack::new(0).unwrap();
+  let mut outer = Generator::new(stack, |yielder: &mut Yielder<(), i32>, ()| {
+    let stack = OsStack::new(0).unwrap();
+    let mut inner = Generator::new(stack, |yielder: &mut Yielder<(), i32>, ()| {
+      yielder.suspend(1);
+      recurse(0);
+    });
+    let result = panic::catch_unwind(AssertUnwindSafe(|| yielder.delegate(&mut inner, ())));
+    assert!(result.unwrap_err().is::<StackOverflow>());
+    assert_eq!(inner.state(), State::Poisoned);
+    yielder.suspend(2);
+  });
+  assert_eq!(outer.try_resume(()), Ok(Some(1)));
+  assert_eq!(outer.try_resume(()), Ok(Some(2)));
+  assert_eq!(outer.try_resume(()), Ok(None));
+}
+
+#[test]
+fn deep_guard_overflow_recovered() {
+  fringe::overflow::install_handler().unwrap();
+  fringe::overflow::set_recoverable(true);
+
+  // A frame much larger than a page can skip over the first few guard pages.
+  let stack = OsStack::builder().guard_pages(4).build().unwrap();
+  let addr = stack.limit() as usize - 3 * 4096;
+  let mut generator = Generator::new(stack, |_yielder: &mut Yielder<usize, ()>, addr| {
+    unsafe { ptr::write_volatile(addr as *mut u8, 0) }
+  });
+  assert_eq!(generator.try_resume(addr), Err(StackOverflow));
+}
@@ -0,0 +1,100 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+extern crate fringe;
+
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+
+use fringe::OsStack;
+use fringe::generator::{GeneratorState, State};
+
+struct SetOnDrop<'a>(&'a Cell<bool>);
+
+impl<'a> Drop for SetOnDrop<'a> {
+  fn drop(&mut self) {
+    self.0.set(true)
+  }
+}
+
+#[test]
+fn borrow_local() {
+  let buffer = vec![1, 2, 3];
+  fringe::scope(|s| {
+    let stack = OsStack::new(0).unwrap();
+    let mut items = s.generator(stack, |yielder, ()| {
+      for item in &buffer { yielder.suspend(*item) }
+      buffer.len()
+    });
+    assert_eq!(items.resume_state(()), GeneratorState::Yielded(1));
+    assert_eq!(items.resume_state(()), GeneratorState::Yielded(2));
+    assert_eq!(items.resume_state(()), GeneratorState::Yielded(3));
+    assert_eq!(items.resume_state(()), GeneratorState::Complete(3));
+    assert_eq!(items.state(), State::Unavailable);
+  });
+  assert_eq!(buffer, vec![1, 2, 3]);
+}
+
+#[test]
+fn borrow_mut_local() {
+  let mut log = Vec::new();
+  fringe::scope(|s| {
+    let stack = OsStack::new(0).unwrap();
+    let mut logger = s.generator(stack, |yielder, mut input| {
+      while input != 0 {
+        log.push(input);
+        input = yielder.suspend(());
+      }
+    });
+    logger.resume(1);
+    logger.resume(2);
+    logger.resume(0);
+  });
+  assert_eq!(log, vec![1, 2]);
+}
+
+#[test]
+fn unwound_at_scope_end() {
+  let dropped = Cell::new(false);
+  fringe::scope(|s| {
+    let stack = OsStack::new(0).unwrap();
+    let mut generator = s.generator(stack, |yielder, ()| {
+      let _flag = SetOnDrop(&dropped);
+      loop { yielder.suspend(()) }
+    });
+    generator.resume(());
+    assert!(!dropped.get());
+  });
+  assert!(dropped.get());
+}
+
+#[test]
+fn unwound_on_panic() {
+  let dropped = Cell::new(false);
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    fringe::scope(|s| {
+      let stack = OsStack::new(0).unwrap();
+      let mut generator = s.generator(stack, |yielder, ()| {
+        let _flag = SetOnDrop(&dropped);
+        loop { yielder.suspend(()) }
+      });
+      generator.resume(());
+      panic!("scope")
+    })
+  }));
+  assert!(result.is_err());
+  assert!(dropped.get());
+}
+
+#[test]
+fn try_generator_too_small() {
+  let local = 1;
+  fringe::scope(|s| {
+    let stack = OsStack::new(4096).unwrap();
+    let result = s.try_generator(stack, |yielder, ()| yielder.suspend(local));
+    assert!(result.is_err());
+  });
+}
@@ -6,7 +6,10 @@
 // copied, modified, or distributed except according to those terms.
 extern crate fringe;
 
-use fringe::{Stack, SliceStack, OwnedStack, OsStack};
+use std::hint::black_box;
+
+use fringe::{Stack, GuardedStack, SliceStack, OwnedStack, OsStack, PaintedStack, Generator};
+use fringe::generator::Yielder;
 
 #[test]
 fn slice_stack() {
@@ -34,3 +37,178 @@ fn one_page_os_stack() {
   // Make sure the topmost page of the stack, at least, is accessible.
   unsafe { *(stack.base().offset(-1)) = 0; }
 }
+
+#[test]
+fn default_os_stack_size() {
+  let stack = OsStack::new(0).unwrap();
+  assert!(stack.base() as usize - stack.limit() as usize >= fringe::RECOMMENDED_STACK_SIZE);
+}
+
+#[test]
+fn os_stack_builder() {
+  let stack = OsStack::builder().size(8192).guard_pages(3).build().unwrap();
+  assert!(stack.base() as usize - stack.limit() as usize >= 8192);
+  assert!(stack.guard_size() >= 3 * 4096);
+  assert_eq!(stack.guard_size() % 3, 0);
+  // Make sure the lowest byte of the stack is accessible.
+  unsafe { *(stack.limit()) = 0; }
+}
+
+#[test]
+fn os_stack_default_guard() {
+  let stack = OsStack::new(0).unwrap();
+  assert!(stack.guard_size() >= fringe::MIN_GUARD_SIZE);
+}
+
+#[test]
+fn os_stack_without_guard() {
+  let err = OsStack::builder().guard_pages(0).build().unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn os_stack_too_large() {
+  let err = OsStack::builder().size(usize::MAX).build().unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  let err = OsStack::builder().size(usize::MAX - 4096).build().unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  let err = OsStack::builder().guard_pages(usize::MAX / 2).build().unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+  let mut stack = OsStack::new(0).unwrap();
+  let err = stack.commit(usize::MAX).unwrap_err();
+  assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+}
+
+#[test]
+fn lazy_os_stack() {
+  let mut stack = OsStack::builder().size(8 << 20).lazy(true).build().unwrap();
+  assert_eq!(stack.reserved_size(), 8 << 20);
+  assert_eq!(stack.resident_size().unwrap(), 0);
+  stack.commit(1 << 20).unwrap();
+  assert!(stack.resident_size().unwrap() >= 1 << 20);
+  stack.decommit().unwrap();
+  #[cfg(target_os = "linux")]
+  assert_eq!(stack.resident_size().unwrap(), 0);
+}
+
+#[inline(never)]
+fn go_deep() {
+  black_box([1u8; 256 << 10]);
+}
+
+#[test]
+fn generator_decommit_unused_stack() {
+  let stack = OsStack::builder().size(1 << 20).lazy(true).build().unwrap();
+  let mut generator = Generator::new(stack, |yielder, ()| {
+    let live = black_box([7u8; 1024]);
+    go_deep();
+    yielder.suspend(0);
+    go_deep();
+    yielder.suspend(live.iter().map(|&x| x as usize).sum());
+  });
+  assert_eq!(generator.resume(()), Some(0));
+  assert!(generator.decommit_unused_stack().unwrap() >= 512 << 10);
+  // The part of the stack in use is left intact.
+  assert_eq!(generator.resume(()), Some(7 * 1024));
+  assert!(generator.decommit_unused_stack().unwrap() >= 512 << 10);
+  assert_eq!(generator.resume(()), None);
+  assert!(generator.decommit_unused_stack().unwrap() >= 512 << 10);
+}
+
+#[test]
+fn generator_decommit_unused_stack_while_delegating() {
+  #[repr(align(16))]
+  struct Memory([u8; 64 << 10]);
+
+  let stack = OsStack::builder().size(1 << 20).lazy(true).build().unwrap();
+  let mut outer = Generator::new(stack, |yielder, ()| {
+    // The stack of the inner generator lies within the one of the outer generator,
+    // above the frames of `delegate`.
+    let mut memory = Memory([0; 64 << 10]);
+    let mut inner = unsafe {
+      Generator::unsafe_new(SliceStack(&mut memory.0), |yielder: &mut Yielder<(), usize>, ()| {
+        yielder.suspend(1);
+        yielder.suspend(2);
+      })
+    };
+    yielder.delegate(&mut inner, ());
+    yielder.suspend(3);
+  });
+  assert_eq!(outer.resume(()), Some(1));
+  assert_eq!(outer.decommit_unused_stack().unwrap(), 0);
+  assert_eq!(outer.resume(()), Some(2));
+  assert_eq!(outer.resume(()), Some(3));
+  assert_eq!(outer.resume(()), None);
+}
+
+// With `hardening`, suspending the outer generator from the inner stack panics instead.
+#[test]
+#[cfg(not(feature = "hardening"))]
+fn generator_decommit_unused_stack_from_scoped_generator() {
+  let stack = OsStack::builder().size(1 << 20).lazy(true).build().unwrap();
+  let mut outer = Generator::new(stack, |yielder: &mut Yielder<(), usize>, ()| {
+    let local = black_box([7u8; 8192]);
+    fringe::scope(|s| {
+      let mut inner = s.generator(OsStack::new(0).unwrap(), |_: &mut Yielder<(), ()>, ()| {
+        // The outer generator is suspended with its stack pointer on the inner stack.
+        yielder.suspend(1);
+      });
+      inner.resume(());
+    });
+    yielder.suspend(local.iter().map(|&byte| byte as usize).sum());
+  });
+  assert_eq!(outer.resume(()), Some(1));
+  assert_eq!(outer.decommit_unused_stack().unwrap(), 0);
+  assert_eq!(outer.resume(()), Some(7 * 8192));
+  assert_eq!(outer.resume(()), None);
+}
+
+#[test]
+fn painted_slice_stack() {
+  let mut memory = [0; 1024];
+  let stack = PaintedStack::from(SliceStack(&mut memory));
+  assert_eq!(stack.high_water(), 0);
+  unsafe { *(stack.base().offset(-100)) = 0; }
+  assert_eq!(stack.high_water(), 100);
+  let stack = stack.into_inner();
+  assert!(stack.0.iter().skip(1024 - 99).all(|&byte| byte == fringe::STACK_PAINT));
+}
+
+#[test]
+fn painted_owned_stack() {
+  let stack = PaintedStack::from(OwnedStack::new(1024));
+  assert_eq!(stack.high_water(), 0);
+  unsafe { *(stack.limit()) = 0; }
+  assert_eq!(stack.high_water(), 1024);
+}
+
+#[test]
+fn painted_unchecked_stack() {
+  let mut memory = [0; 1024];
+  let stack = unsafe { PaintedStack::new(SliceStack(&mut memory)) };
+  unsafe { *(stack.base().offset(-10)) = 0; }
+  assert_eq!(stack.high_water(), 10);
+}
+
+#[test]
+fn generator_stack_high_water() {
+  let stack = PaintedStack::from(OsStack::new(0).unwrap());
+  let mut generator = Generator::new(stack, |yielder, depth: usize| {
+    let buffer = black_box([1u8; 8192]);
+    yielder.suspend(buffer[depth]);
+  });
+  let untouched = generator.stack_high_water();
+  assert!(untouched < 1024, "{}", untouched);
+  generator.resume(0);
+  let used = generator.stack_high_water();
+  assert!(used > 8192 && used < 8192 + 16384, "{}", used);
+}
+
+#[test]
+fn measure_stack_usage() {
+  let (value, small) = fringe::measure_stack_usage(0, || 1);
+  assert_eq!(value, 1);
+  let (value, large) = fringe::measure_stack_usage(0, || black_box([1u8; 16384])[0] + 1);
+  assert_eq!(value, 2);
+  assert!(small < 4096 && large >= 16384, "{} {}", small, large);
+}
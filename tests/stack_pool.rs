@@ -0,0 +1,131 @@
+// This file is part of libfringe, a low-level green threading library.
+// Copyright (c) whitequark <whitequark@whitequark.org>
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+#![cfg(unix)]
+extern crate fringe;
+
+use std::thread;
+
+use fringe::{Stack, StackPool, SyncStackPool, StackPoolStats, Generator, SendGenerator};
+
+fn size(stack: &dyn Stack) -> usize {
+  stack.base() as usize - stack.limit() as usize
+}
+
+#[test]
+fn reused() {
+  let pool = StackPool::new();
+  let stack = pool.get(0).unwrap();
+  let base = stack.base();
+  drop(stack);
+  let stack = pool.get(0).unwrap();
+  assert_eq!(stack.base(), base);
+  assert_eq!(pool.stats(), StackPoolStats { allocated: 1, reused: 1, returned: 1, released: 0, idle: 0 });
+}
+
+#[test]
+fn generators() {
+  let pool = StackPool::new();
+  for i in 0..10 {
+    let mut generator = Generator::new(pool.get(0).unwrap(), move |yielder, ()| {
+      yielder.suspend(i);
+    });
+    assert_eq!(generator.resume(()), Some(i));
+  }
+  assert_eq!(pool.stats().allocated, 1);
+  assert_eq!(pool.stats().reused, 9);
+}
+
+#[test]
+fn size_classes() {
+  let pool = StackPool::builder().size_classes(&[256 << 10, 64 << 10]).build();
+  let small = pool.get(100).unwrap();
+  assert!(size(&small) >= 64 << 10);
+  assert!(size(&small) < 256 << 10);
+  let medium = pool.get(100 << 10).unwrap();
+  assert!(size(&medium) >= 256 << 10);
+  let large = pool.get(1 << 20).unwrap();
+  assert!(size(&large) >= 1 << 20);
+  drop((small, medium, large));
+  assert_eq!(pool.stats(), StackPoolStats { allocated: 3, reused: 0, returned: 2, released: 1, idle: 2 });
+
+  // A stack is only reused for requests of its size class.
+  let _medium = pool.get(200 << 10).unwrap();
+  let _small = pool.get(0x1000).unwrap();
+  let _other = pool.get(0x2000).unwrap();
+  assert_eq!(pool.stats().reused, 2);
+  assert_eq!(pool.stats().allocated, 4);
+}
+
+#[test]
+fn cap() {
+  let pool = StackPool::builder().cap(1).build();
+  let stacks = (pool.get(0).unwrap(), pool.get(0).unwrap());
+  drop(stacks);
+  assert_eq!(pool.stats(), StackPoolStats { allocated: 2, reused: 0, returned: 1, released: 1, idle: 1 });
+}
+
+#[test]
+fn cap_decommitted() {
+  let pool = StackPool::builder().cap(1).decommit(true).build();
+  let stacks = (pool.get(0).unwrap(), pool.get(0).unwrap(), pool.get(0).unwrap());
+  drop(stacks);
+  assert_eq!(pool.stats(), StackPoolStats { allocated: 3, reused: 0, returned: 1, released: 2, idle: 1 });
+  let _stack = pool.get(0).unwrap();
+  assert_eq!(pool.stats().reused, 1);
+}
+
+#[test]
+fn outlives_pool() {
+  let pool = StackPool::new();
+  let stack = pool.get(0).unwrap();
+  drop(pool);
+  drop(stack);
+}
+
+#[test]
+fn decommitted() {
+  let pool = StackPool::builder().decommit(true).build();
+  let stack = pool.get(0).unwrap();
+  let base = stack.base();
+  unsafe { *base.offset(-1) = 1 }
+  drop(stack);
+  let stack = pool.get(0).unwrap();
+  assert_eq!(stack.base(), base);
+  #[cfg(target_os = "linux")]
+  assert_eq!(unsafe { *base.offset(-1) }, 0);
+}
+
+#[test]
+fn shared() {
+  let pool = SyncStackPool::new();
+  let threads = (0..4).map(|i| {
+    let pool = pool.clone();
+    thread::spawn(move || {
+      for j in 0..10 {
+        let mut generator = unsafe {
+          SendGenerator::new(pool.get(0).unwrap(), move |yielder, ()| {
+            yielder.suspend(i * 10 + j);
+          })
+        };
+        assert_eq!(generator.resume(()), Some(i * 10 + j));
+      }
+    })
+  }).collect::<Vec<_>>();
+  for thread in threads { thread.join().unwrap() }
+  let stats = pool.stats();
+  assert!(stats.allocated <= 4);
+  assert_eq!(stats.allocated + stats.reused, 40);
+  assert_eq!(stats.idle, stats.allocated);
+}
+
+#[test]
+fn pooled_stack_sent() {
+  let pool = SyncStackPool::new();
+  let stack = pool.get(0).unwrap();
+  thread::spawn(move || drop(stack)).join().unwrap();
+  assert_eq!(pool.stats().idle, 1);
+}